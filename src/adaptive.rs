@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use crate::shared::Mode;
+
+/// Number of bits of a symbol sent the first time it is seen.
+pub fn literal_bits(mode: Mode) -> u32 {
+    match mode {
+        // Every Unicode scalar value fits in 21 bits
+        Mode::Text => 21,
+        Mode::Bytes => 8,
+    }
+}
+
+#[derive(Clone, Debug)]
+struct AdaptiveNode {
+    weight: u64,
+    parent: Option<usize>,
+    /// Left and right child, `None` for leaves.
+    children: Option<(usize, usize)>,
+    symbol: Option<u32>,
+}
+
+/// Huffman tree updated after every symbol with the FGK algorithm, so both
+/// sides derive the same codes from the symbols seen so far and no table
+/// is stored.
+///
+/// Symbols not seen yet are sent as the code of the NYT (not yet
+/// transmitted) leaf followed by the symbol itself. Nodes are kept in
+/// `order` by decreasing weight, the root first and the NYT leaf last, which
+/// is the sibling property FGK maintains. The nodes of a weight form a block
+/// of `order`, whose first node is its leader.
+#[derive(Clone, Debug)]
+pub struct AdaptiveTree {
+    nodes: Vec<AdaptiveNode>,
+    order: Vec<usize>,
+    /// Position of every node in `order`.
+    position: Vec<usize>,
+    /// Block of every node.
+    blocks: Vec<usize>,
+    /// Position in `order` of the leader of every block. There are never
+    /// more blocks than nodes, blocks left without nodes are reused.
+    leaders: Vec<usize>,
+    free_blocks: Vec<usize>,
+    leaves: HashMap<u32, usize>,
+    nyt: usize,
+}
+
+impl AdaptiveTree {
+    pub fn new() -> Self {
+        AdaptiveTree {
+            nodes: vec![AdaptiveNode {
+                weight: 0,
+                parent: None,
+                children: None,
+                symbol: None,
+            }],
+            order: vec![0],
+            position: vec![0],
+            blocks: vec![0],
+            leaders: vec![0],
+            free_blocks: Vec::new(),
+            leaves: HashMap::new(),
+            nyt: 0,
+        }
+    }
+
+    pub fn root(&self) -> usize {
+        0
+    }
+
+    pub fn nyt(&self) -> usize {
+        self.nyt
+    }
+
+    /// Leaf of `symbol` when it was seen before.
+    pub fn leaf(&self, symbol: u32) -> Option<usize> {
+        self.leaves.get(&symbol).copied()
+    }
+
+    /// Every symbol seen so far, in no particular order.
+    pub fn symbols(&self) -> impl Iterator<Item = u32> + '_ {
+        self.leaves.keys().copied()
+    }
+
+    pub fn symbol(&self, node: usize) -> Option<u32> {
+        self.nodes[node].symbol
+    }
+
+    /// The right child of `node` for a 1 bit and the left one for a 0 bit,
+    /// `None` for leaves.
+    pub fn child(&self, node: usize, bit: bool) -> Option<usize> {
+        self.nodes[node]
+            .children
+            .map(|(left, right)| if bit { right } else { left })
+    }
+
+    /// The parent of `node` and the bit leading from it to `node`.
+    pub fn parent(&self, node: usize) -> Option<(usize, bool)> {
+        let parent = self.nodes[node].parent?;
+        let (_, right) = self.nodes[parent].children?;
+        Some((parent, right == node))
+    }
+
+    /// Counts one more occurrence of `symbol`, adding a leaf for it when it
+    /// is new.
+    pub fn update(&mut self, symbol: u32) {
+        let mut node = match self.leaf(symbol) {
+            Some(leaf) => leaf,
+            None => self.split_nyt(symbol),
+        };
+
+        loop {
+            // Move the node in place of the leader of its weight, so it
+            // stays ordered once its weight grows. The parent only has the
+            // same weight when the sibling is the NYT leaf, it is then the
+            // leader and grows right after, so the node goes just behind it
+            let weight = self.nodes[node].weight;
+            let first = self.leaders[self.blocks[node]];
+            let mut leader = self.order[first];
+            if Some(leader) == self.nodes[node].parent {
+                leader = self.order[first + 1];
+            }
+            if leader != node {
+                self.swap(node, leader);
+            }
+
+            self.nodes[node].weight += 1;
+            self.promote(node, weight);
+            match self.nodes[node].parent {
+                Some(parent) => node = parent,
+                None => break,
+            }
+        }
+    }
+
+    /// Moves `node` from the block of `weight` to the one above it. The
+    /// node is the leader of its block, or right behind its parent leading
+    /// it, which then grows next.
+    ///
+    /// The node that grew behind its parent is the only one out of order,
+    /// so the blocks next to the node are found within two positions.
+    fn promote(&mut self, node: usize, weight: u64) {
+        let position = self.position[node];
+        let weight_of = |tree: &Self, position: usize| tree.nodes[tree.order[position]].weight;
+
+        // The block goes on after the node, past a node that grew behind
+        // it, or has no other node
+        let block = self.blocks[node];
+        if self.leaders[block] == position {
+            let next = (position + 1..self.order.len().min(position + 3))
+                .find(|&next| weight_of(self, next) <= weight)
+                .filter(|&next| weight_of(self, next) == weight);
+            match next {
+                Some(next) => self.leaders[block] = next,
+                None => self.free_blocks.push(block),
+            }
+        }
+
+        // The block above ends before the node, or before its parent when
+        // the node grew behind it. Otherwise it starts after the node when
+        // a child grew behind it, or does not exist yet
+        let before = (position.saturating_sub(2)..position)
+            .rev()
+            .find(|&before| weight_of(self, before) > weight)
+            .filter(|&before| weight_of(self, before) == weight + 1);
+        let after = Some(position + 1)
+            .filter(|&after| after < self.order.len() && weight_of(self, after) == weight + 1);
+        self.blocks[node] = match (before, after) {
+            (Some(before), _) => self.blocks[self.order[before]],
+            (None, Some(after)) => {
+                let block = self.blocks[self.order[after]];
+                self.leaders[block] = position;
+                block
+            }
+            (None, None) => {
+                let block = self.free_blocks.pop().unwrap_or_else(|| {
+                    self.leaders.push(0);
+                    self.leaders.len() - 1
+                });
+                self.leaders[block] = position;
+                block
+            }
+        };
+    }
+
+    /// Turns the NYT leaf into an internal node holding a new NYT leaf and a
+    /// leaf for `symbol`, which is returned.
+    fn split_nyt(&mut self, symbol: u32) -> usize {
+        let parent = self.nyt;
+        let leaf = self.push(parent, Some(symbol));
+        let nyt = self.push(parent, None);
+
+        self.nodes[parent].children = Some((nyt, leaf));
+        self.leaves.insert(symbol, leaf);
+        self.nyt = nyt;
+
+        leaf
+    }
+
+    fn push(&mut self, parent: usize, symbol: Option<u32>) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(AdaptiveNode {
+            weight: 0,
+            parent: Some(parent),
+            children: None,
+            symbol,
+        });
+        self.position.push(self.order.len());
+        self.order.push(node);
+        // New nodes are last with the other nodes of weight 0
+        self.blocks.push(self.blocks[parent]);
+
+        node
+    }
+
+    /// Swaps the subtrees rooted at `a` and `b`, neither being the root or
+    /// an ancestor of the other.
+    fn swap(&mut self, a: usize, b: usize) {
+        let parent_a = self.nodes[a].parent.unwrap();
+        let parent_b = self.nodes[b].parent.unwrap();
+
+        if parent_a == parent_b {
+            let children = self.nodes[parent_a].children.as_mut().unwrap();
+            *children = (children.1, children.0);
+        } else {
+            self.replace_child(parent_a, a, b);
+            self.replace_child(parent_b, b, a);
+            self.nodes[a].parent = Some(parent_b);
+            self.nodes[b].parent = Some(parent_a);
+        }
+
+        let (position_a, position_b) = (self.position[a], self.position[b]);
+        self.order.swap(position_a, position_b);
+        self.position[a] = position_b;
+        self.position[b] = position_a;
+    }
+
+    fn replace_child(&mut self, parent: usize, old: usize, new: usize) {
+        let (left, right) = self.nodes[parent].children.as_mut().unwrap();
+        if *left == old {
+            *left = new;
+        } else {
+            *right = new;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decoder, encoder::Encoder};
+
+    fn round_trip(input: &[u8], mode: Mode) {
+        let mut encoder = Encoder::new(Vec::new(), mode)
+            .with_adaptive(true)
+            .with_block_size(1000);
+        encoder.encode(&mut &input[..]).unwrap();
+
+        assert_eq!(decoder::decode(encoder.into_inner()).unwrap(), input);
+    }
+
+    /// Checks that `order` is by decreasing weight and that every block
+    /// holds the nodes of one weight, led by the first of them.
+    fn assert_blocks(tree: &AdaptiveTree) {
+        for (position, &node) in tree.order.iter().enumerate() {
+            assert_eq!(tree.position[node], position);
+            let weight = tree.nodes[node].weight;
+            let first = tree
+                .order
+                .iter()
+                .position(|&n| tree.nodes[n].weight == weight);
+            assert_eq!(tree.leaders[tree.blocks[node]], first.unwrap());
+            if position > 0 {
+                assert!(tree.nodes[tree.order[position - 1]].weight >= weight);
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_input() {
+        round_trip(b"", Mode::Text);
+        round_trip(b"", Mode::Bytes);
+    }
+
+    #[test]
+    fn test_single_symbol() {
+        round_trip(b"a", Mode::Text);
+        round_trip(&[0xff; 3000], Mode::Bytes);
+    }
+
+    #[test]
+    fn test_two_symbols() {
+        round_trip(b"ab", Mode::Text);
+        round_trip(&b"aab".repeat(1000), Mode::Bytes);
+    }
+
+    #[test]
+    fn test_unicode() {
+        round_trip(
+            "naïve café, 日本語, 🦀🦀🦀 ".repeat(200).as_bytes(),
+            Mode::Text,
+        );
+    }
+
+    #[test]
+    fn test_blocks_follow_weights() {
+        let mut tree = AdaptiveTree::new();
+        for i in 0..3000u32 {
+            tree.update(i.wrapping_mul(2_654_435_761) % 97 % (i / 30 + 1));
+            assert_blocks(&tree);
+        }
+    }
+}
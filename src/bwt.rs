@@ -0,0 +1,663 @@
+use std::io::ErrorKind;
+
+use crate::{error::Error, shared::TOKEN_BASE};
+
+/// Digits of a run of repeated symbols in bijective base 2, `RUN_A` worth
+/// one and `RUN_B` two times the weight of its position, as in bzip2.
+pub const RUN_A: u32 = TOKEN_BASE;
+pub const RUN_B: u32 = TOKEN_BASE + 1;
+/// Tokens of a symbol found at position `n` of the move-to-front list,
+/// `MTF_BASE + n - 1`. Position 0 is only sent as part of a run.
+pub const MTF_BASE: u32 = TOKEN_BASE + 2;
+
+/// Burrows-Wheeler transform of `symbols`, then move-to-front and zero
+/// run-length coding.
+///
+/// The block gets an end marker sorting before every symbol, so the
+/// position of the original rotation is found from where the marker ends
+/// up rather than stored. The move-to-front list starts with the end
+/// marker followed by the distinct symbols of the block in ascending
+/// order, so every symbol is sent as its position in the list. Returns
+/// those distinct symbols followed by the tokens.
+pub fn transform(symbols: &[u32]) -> Vec<u32> {
+    // Symbols are ranked from 1, the end marker takes 0
+    let (mut text, alphabet) = dense_ids(symbols);
+    text.push(0);
+
+    let last = sa_is(&text, alphabet.len() + 1)
+        .into_iter()
+        .map(|suffix| match suffix {
+            0 => 0,
+            suffix => text[suffix as usize - 1],
+        })
+        .collect::<Vec<_>>();
+
+    let mut tokens = alphabet;
+    move_to_front(&mut tokens, &last);
+    tokens
+}
+
+/// Undoes `transform` given its distinct symbols `alphabet` and the tokens
+/// after them, the block being at most `max_len` symbols long.
+pub fn invert(alphabet: &[u32], tokens: &[u32], max_len: u64) -> Result<Vec<u32>, Error> {
+    let last = move_from_front(tokens, alphabet.len() + 1, max_len + 1)?;
+    inverse_bwt(alphabet, &last)
+}
+
+/// Numbers every distinct value of `values` from 1 in sorted order.
+/// Returns the numbers of `values` and the distinct values.
+fn dense_ids(values: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let size = values.iter().max().map_or(0, |&max| max as usize + 1);
+    let mut ids = vec![0u32; size];
+    for &value in values {
+        ids[value as usize] = 1;
+    }
+    let mut distinct = Vec::new();
+    for (value, id) in ids.iter_mut().enumerate() {
+        if *id != 0 {
+            distinct.push(value as u32);
+            *id = distinct.len() as u32;
+        }
+    }
+
+    (
+        values.iter().map(|&value| ids[value as usize]).collect(),
+        distinct,
+    )
+}
+
+/// Marks an empty slot of the suffix array.
+const EMPTY: u32 = u32::MAX;
+
+/// Suffix array of `text`, whose values are below `alphabet` and which ends
+/// with a 0 found nowhere else, by induced sorting (SA-IS).
+///
+/// Suffixes are S-type when smaller than the suffix after them and L-type
+/// otherwise, and an S-type suffix after an L-type one is a leftmost S
+/// (LMS) suffix. Once the LMS suffixes are sorted, one pass from the front
+/// places every L-type suffix after the suffix following it and one pass
+/// from the back every S-type suffix. Sorting the LMS suffixes is the same
+/// problem on a string at most half as long, made of the ranks of the
+/// substrings between them, which are sorted by the same passes.
+fn sa_is(text: &[u32], alphabet: usize) -> Vec<u32> {
+    let n = text.len();
+    if n == 1 {
+        return vec![0];
+    }
+
+    let mut s_type = vec![false; n];
+    s_type[n - 1] = true;
+    for i in (0..n - 1).rev() {
+        s_type[i] = text[i] < text[i + 1] || (text[i] == text[i + 1] && s_type[i + 1]);
+    }
+    let is_lms = |i: usize| i > 0 && s_type[i] && !s_type[i - 1];
+
+    let mut sizes = vec![0u32; alphabet];
+    for &c in text {
+        sizes[c as usize] += 1;
+    }
+
+    // Sort the LMS substrings by inducing from their unsorted starts
+    let lms = (1..n).filter(|&i| is_lms(i)).collect::<Vec<_>>();
+    let mut sa = vec![EMPTY; n];
+    let mut tails = bucket_tails(&sizes);
+    for &i in lms.iter().rev() {
+        let tail = &mut tails[text[i] as usize];
+        *tail -= 1;
+        sa[*tail as usize] = i as u32;
+    }
+    induce(text, &s_type, &sizes, &mut sa);
+
+    // Name the LMS substrings by their rank, equal ones sharing a name
+    let sorted = sa
+        .iter()
+        .map(|&i| i as usize)
+        .filter(|&i| is_lms(i))
+        .collect::<Vec<_>>();
+    let mut names = vec![EMPTY; n];
+    let mut name = 0;
+    for (w, &i) in sorted.iter().enumerate() {
+        if w > 0 && !lms_equal(text, &s_type, sorted[w - 1], i) {
+            name += 1;
+        }
+        names[i] = name;
+    }
+    let reduced = lms.iter().map(|&i| names[i]).collect::<Vec<_>>();
+
+    // Sort the LMS suffixes, recursing while names repeat
+    let order = if (name as usize) + 1 < lms.len() {
+        sa_is(&reduced, name as usize + 1)
+    } else {
+        let mut order = vec![0; lms.len()];
+        for (j, &name) in reduced.iter().enumerate() {
+            order[name as usize] = j as u32;
+        }
+        order
+    };
+
+    sa.fill(EMPTY);
+    let mut tails = bucket_tails(&sizes);
+    for &j in order.iter().rev() {
+        let i = lms[j as usize];
+        let tail = &mut tails[text[i] as usize];
+        *tail -= 1;
+        sa[*tail as usize] = i as u32;
+    }
+    induce(text, &s_type, &sizes, &mut sa);
+
+    sa
+}
+
+/// Places the L-type suffixes from the front of their buckets, then the
+/// S-type ones from the back, each after the suffix following it.
+fn induce(text: &[u32], s_type: &[bool], sizes: &[u32], sa: &mut [u32]) {
+    let mut heads = bucket_tails(sizes);
+    for (head, &size) in heads.iter_mut().zip(sizes) {
+        *head -= size;
+    }
+    for j in 0..sa.len() {
+        if sa[j] == EMPTY || sa[j] == 0 {
+            continue;
+        }
+        let i = sa[j] as usize - 1;
+        if !s_type[i] {
+            let head = &mut heads[text[i] as usize];
+            sa[*head as usize] = i as u32;
+            *head += 1;
+        }
+    }
+
+    let mut tails = bucket_tails(sizes);
+    for j in (0..sa.len()).rev() {
+        if sa[j] == EMPTY || sa[j] == 0 {
+            continue;
+        }
+        let i = sa[j] as usize - 1;
+        if s_type[i] {
+            let tail = &mut tails[text[i] as usize];
+            *tail -= 1;
+            sa[*tail as usize] = i as u32;
+        }
+    }
+}
+
+/// End of the bucket of every value, the suffixes starting with it.
+fn bucket_tails(sizes: &[u32]) -> Vec<u32> {
+    sizes
+        .iter()
+        .scan(0, |end, &size| {
+            *end += size;
+            Some(*end)
+        })
+        .collect()
+}
+
+/// Whether the LMS substrings starting at `a` and `b`, up to and including
+/// the next LMS position, are equal.
+fn lms_equal(text: &[u32], s_type: &[bool], a: usize, b: usize) -> bool {
+    let n = text.len();
+    // The substring of the final 0 is the only one holding it
+    if a == n - 1 || b == n - 1 {
+        return a == b;
+    }
+
+    let is_lms = |i: usize| s_type[i] && !s_type[i - 1];
+    for k in 0.. {
+        if text[a + k] != text[b + k] || s_type[a + k] != s_type[b + k] {
+            return false;
+        }
+        if k > 0 && is_lms(a + k) {
+            return is_lms(b + k);
+        }
+    }
+    unreachable!()
+}
+
+/// Appends the move-to-front and zero run-length coding of the last column
+/// to `tokens`, its symbols numbered as by `dense_ids`.
+fn move_to_front(tokens: &mut Vec<u32>, last: &[u32]) {
+    let size = last.iter().max().map_or(0, |&max| max as usize + 1);
+    let mut list = MtfList::new(size, last.len());
+    let mut run = 0u64;
+
+    for &id in last {
+        let pos = list.position(id);
+        if pos == 0 {
+            run += 1;
+            continue;
+        }
+        push_run(tokens, run);
+        tokens.push(MTF_BASE + pos as u32 - 1);
+        list.move_to_front(id);
+        run = 0;
+    }
+    push_run(tokens, run);
+}
+
+fn push_run(tokens: &mut Vec<u32>, mut run: u64) {
+    while run > 0 {
+        if run & 1 == 1 {
+            tokens.push(RUN_A);
+            run = (run - 1) / 2;
+        } else {
+            tokens.push(RUN_B);
+            run = (run - 2) / 2;
+        }
+    }
+}
+
+/// Rebuilds the last column from the tokens of `move_to_front`, numbered
+/// below `size`, failing when it would be longer than `max_len`.
+fn move_from_front(tokens: &[u32], size: usize, max_len: u64) -> Result<Vec<u32>, Error> {
+    // Size the column from the tokens before expanding any run, so runs
+    // only take the memory the tokens really stand for
+    let len = column_len(tokens)
+        .filter(|&len| len <= max_len)
+        .ok_or(Error::InvalidBlock)?;
+    let mut last = Vec::new();
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| last.try_reserve_exact(len).ok())
+        .ok_or_else(|| Error::Io(ErrorKind::OutOfMemory.into()))?;
+
+    let moves = tokens
+        .iter()
+        .filter(|&&token| token != RUN_A && token != RUN_B)
+        .count();
+    let mut list = MtfList::new(size, moves);
+    let mut run = 0u64;
+    let mut weight = 1u64;
+
+    for &token in tokens {
+        if token == RUN_A || token == RUN_B {
+            let digit = if token == RUN_A { 1 } else { 2 };
+            run += weight * digit;
+            weight = weight.saturating_mul(2);
+            continue;
+        }
+        expand_run(&mut last, &list, run)?;
+        run = 0;
+        weight = 1;
+
+        let id = token
+            .checked_sub(MTF_BASE)
+            .and_then(|pos| list.get(pos as usize + 1))
+            .ok_or(Error::InvalidBlock)?;
+        list.move_to_front(id);
+        last.push(id);
+    }
+    expand_run(&mut last, &list, run)?;
+
+    Ok(last)
+}
+
+/// Number of symbols `tokens` expand to, `None` when it overflows.
+fn column_len(tokens: &[u32]) -> Option<u64> {
+    let mut len = 0u64;
+    let mut weight = 1u64;
+
+    for &token in tokens {
+        if token == RUN_A || token == RUN_B {
+            let digit = if token == RUN_A { 1 } else { 2 };
+            len = len.checked_add(weight.checked_mul(digit)?)?;
+            weight = weight.saturating_mul(2);
+        } else {
+            len = len.checked_add(1)?;
+            weight = 1;
+        }
+    }
+
+    Some(len)
+}
+
+/// Appends `run` repeats of the symbol in front of `list`.
+fn expand_run(last: &mut Vec<u32>, list: &MtfList, run: u64) -> Result<(), Error> {
+    if run == 0 {
+        return Ok(());
+    }
+    let front = list.get(0).ok_or(Error::InvalidBlock)?;
+    last.resize(last.len() + run as usize, front);
+
+    Ok(())
+}
+
+/// Longest move-to-front list that is searched one entry at a time.
+const SHORT_LIST: usize = 256;
+
+/// Move-to-front list of the numbers below a size, in ascending order at
+/// first.
+///
+/// Short lists are searched one entry at a time, which is quickest for the
+/// small positions most symbols have. Longer ones would make that take
+/// time in the product of the block length and its alphabet, so they find
+/// positions in logarithmic time instead.
+enum MtfList {
+    Short(Vec<u32>),
+    Long(SlotList),
+}
+
+impl MtfList {
+    /// List of the numbers below `size`, which can take up to `moves` moves
+    /// to the front.
+    fn new(size: usize, moves: usize) -> Self {
+        if size <= SHORT_LIST {
+            MtfList::Short((0..size as u32).collect())
+        } else {
+            MtfList::Long(SlotList::new(size, moves))
+        }
+    }
+
+    /// Position of `number` in the list.
+    fn position(&self, number: u32) -> usize {
+        match self {
+            MtfList::Short(list) => list.iter().position(|&n| n == number).unwrap(),
+            MtfList::Long(list) => list.position(number),
+        }
+    }
+
+    /// Number at position `pos` of the list, `None` past its end.
+    fn get(&self, pos: usize) -> Option<u32> {
+        match self {
+            MtfList::Short(list) => list.get(pos).copied(),
+            MtfList::Long(list) => list.get(pos),
+        }
+    }
+
+    fn move_to_front(&mut self, number: u32) {
+        match self {
+            MtfList::Short(list) => {
+                let pos = list.iter().position(|&n| n == number).unwrap();
+                list[..=pos].rotate_right(1);
+            }
+            MtfList::Long(list) => list.move_to_front(number),
+        }
+    }
+}
+
+/// Move-to-front list keeping every number in a slot, the list being the
+/// occupied slots in order.
+///
+/// A number moved to the front takes a free slot before all the others,
+/// and a Fenwick tree counts the occupied slots before any slot.
+struct SlotList {
+    /// Fenwick tree over the slots, from 1, of their occupied counts.
+    counts: Vec<u32>,
+    /// Slot of every number.
+    slots: Vec<usize>,
+    /// Number in every occupied slot.
+    numbers: Vec<u32>,
+    /// Slot of the number in front.
+    front: usize,
+}
+
+impl SlotList {
+    fn new(size: usize, moves: usize) -> Self {
+        let len = moves + size;
+        let mut counts = vec![0u32; len + 1];
+        counts[moves + 1..].fill(1);
+        for i in 1..=len {
+            let parent = i + (i & i.wrapping_neg());
+            if parent <= len {
+                counts[parent] += counts[i];
+            }
+        }
+
+        let mut numbers = vec![0; moves];
+        numbers.extend(0..size as u32);
+        SlotList {
+            counts,
+            slots: (moves..len).collect(),
+            numbers,
+            front: moves,
+        }
+    }
+
+    fn position(&self, number: u32) -> usize {
+        // Most symbols repeat the one before them
+        let mut i = self.slots[number as usize];
+        if i == self.front {
+            return 0;
+        }
+        let mut pos = 0;
+        while i > 0 {
+            pos += self.counts[i];
+            i &= i - 1;
+        }
+
+        pos as usize
+    }
+
+    fn get(&self, pos: usize) -> Option<u32> {
+        if pos >= self.slots.len() {
+            return None;
+        }
+        if pos == 0 {
+            return Some(self.numbers[self.front]);
+        }
+
+        // Last slot with at most `pos` occupied ones up to it
+        let mut slot = 0;
+        let mut rest = pos as u32;
+        let mut step = (self.counts.len() - 1).checked_next_power_of_two()?;
+        while step > 0 {
+            let next = slot + step;
+            if next < self.counts.len() && self.counts[next] <= rest {
+                slot = next;
+                rest -= self.counts[next];
+            }
+            step /= 2;
+        }
+
+        Some(self.numbers[slot])
+    }
+
+    fn move_to_front(&mut self, number: u32) {
+        self.add(self.slots[number as usize], u32::MAX);
+        self.front -= 1;
+        self.slots[number as usize] = self.front;
+        self.numbers[self.front] = number;
+        self.add(self.front, 1);
+    }
+
+    /// Adds `delta`, wrapping, to the count of `slot`.
+    fn add(&mut self, slot: usize, delta: u32) {
+        let mut i = slot + 1;
+        while i < self.counts.len() {
+            self.counts[i] = self.counts[i].wrapping_add(delta);
+            i += i & i.wrapping_neg();
+        }
+    }
+}
+
+/// Rebuilds the block from the last column of its sorted rotations, whose
+/// symbols are numbered from 1 in `alphabet` and the end marker is 0.
+///
+/// The first rotation starts with the end marker, so its last symbol is the
+/// last one of the block. Stepping from a rotation to the one starting with
+/// its last symbol goes back through the block one symbol at a time.
+fn inverse_bwt(alphabet: &[u32], last: &[u32]) -> Result<Vec<u32>, Error> {
+    if last.iter().filter(|&&id| id == 0).count() != 1 {
+        return Err(Error::InvalidBlock);
+    }
+
+    // First row starting with every symbol
+    let mut next = vec![0u32; alphabet.len() + 2];
+    for &id in last {
+        next[id as usize + 1] += 1;
+    }
+    for id in 1..next.len() {
+        next[id] += next[id - 1];
+    }
+    // Symbol of every row with the row of the rotation starting with it,
+    // together so that each step touches a single entry
+    let rows = last
+        .iter()
+        .map(|&id| {
+            let row = next[id as usize];
+            next[id as usize] += 1;
+            (id, row)
+        })
+        .collect::<Vec<_>>();
+
+    let mut symbols = vec![0; last.len() - 1];
+    let mut row = 0;
+    for symbol in symbols.iter_mut().rev() {
+        let (id, next) = rows[row];
+        if id == 0 {
+            return Err(Error::InvalidBlock);
+        }
+        *symbol = alphabet[id as usize - 1];
+        row = next as usize;
+    }
+    if rows[row].0 != 0 {
+        return Err(Error::InvalidBlock);
+    }
+
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{coder::Coder, decoder, encoder::Encoder, shared::Mode};
+
+    fn round_trip(symbols: &[u32]) {
+        let tokens = transform(symbols);
+        let (alphabet, tokens) = tokens.split_at(dense_ids(symbols).1.len());
+        assert_eq!(
+            invert(alphabet, tokens, symbols.len() as u64).unwrap(),
+            symbols
+        );
+    }
+
+    fn encode(input: &[u8], mode: Mode, block_size: usize) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), mode)
+            .with_coder(Coder::Bwt)
+            .with_block_size(block_size);
+        encoder.encode(&mut &input[..]).unwrap();
+
+        decoder::decode(encoder.into_inner()).unwrap()
+    }
+
+    fn suffix_array(text: &[u32]) -> Vec<u32> {
+        let mut sa = (0..text.len() as u32).collect::<Vec<_>>();
+        sa.sort_by(|&a, &b| text[a as usize..].cmp(&text[b as usize..]));
+        sa
+    }
+
+    fn periodic(len: usize, period: u32) -> Vec<u32> {
+        (0..len as u32).map(|i| 97 + i % period).collect()
+    }
+
+    #[test]
+    fn test_sa_is() {
+        let texts = [
+            vec![0],
+            vec![1, 0],
+            vec![1, 1, 1, 1, 0],
+            vec![2, 1, 2, 1, 2, 1, 0],
+            vec![3, 1, 2, 1, 2, 1, 3, 1, 2, 1, 1, 0],
+            (0..2000).map(|i| 1 + (i * i % 7)).chain([0]).collect(),
+            (0..2000).map(|i| 1 + i % 3).chain([0]).collect(),
+        ];
+        for text in texts {
+            let alphabet = *text.iter().max().unwrap() as usize + 1;
+            assert_eq!(sa_is(&text, alphabet), suffix_array(&text));
+        }
+    }
+
+    #[test]
+    fn test_mtf_lists() {
+        for size in [1, 5, SHORT_LIST, SHORT_LIST + 1, 1000] {
+            let moves = (0..3000u32)
+                .map(|i| i.wrapping_mul(2_654_435_761) % size as u32 % (i % 40 + 1))
+                .collect::<Vec<_>>();
+            let mut list = MtfList::new(size, moves.len());
+            let mut expected = (0..size as u32).collect::<Vec<_>>();
+            for &number in &moves {
+                let pos = expected.iter().position(|&n| n == number).unwrap();
+                assert_eq!(list.position(number), pos);
+                assert_eq!(list.get(pos), Some(number));
+                list.move_to_front(number);
+                expected[..=pos].rotate_right(1);
+            }
+            for (pos, &number) in expected.iter().enumerate() {
+                assert_eq!(list.get(pos), Some(number));
+            }
+            assert_eq!(list.get(size), None);
+        }
+    }
+
+    #[test]
+    fn test_runs() {
+        let digits = |run| {
+            let mut tokens = Vec::new();
+            push_run(&mut tokens, run);
+            tokens
+        };
+        assert_eq!(digits(0), []);
+        assert_eq!(digits(1), [RUN_A]);
+        assert_eq!(digits(2), [RUN_B]);
+        assert_eq!(digits(3), [RUN_A, RUN_A]);
+        assert_eq!(digits(4), [RUN_B, RUN_A]);
+        assert_eq!(digits(5), [RUN_A, RUN_B]);
+        assert_eq!(digits(6), [RUN_B, RUN_B]);
+        for run in (0..2000).chain([u32::MAX as u64, u64::MAX / 2]) {
+            assert_eq!(column_len(&digits(run)), Some(run));
+        }
+        assert_eq!(column_len(&[RUN_B; 64]), None);
+    }
+
+    #[test]
+    fn test_round_trips() {
+        round_trip(&[]);
+        round_trip(&[7]);
+        round_trip(&[0; 5000]);
+        round_trip(&[0x10ffff, 0, 0x10ffff]);
+        round_trip(&periodic(5000, 1));
+        round_trip(&periodic(5000, 2));
+        round_trip(&periodic(4999, 7));
+        round_trip(&periodic(5000, 600));
+        round_trip(&(0..1000).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_invalid_tokens() {
+        let symbols = periodic(100, 3);
+        let tokens = transform(&symbols);
+        let (alphabet, tokens) = tokens.split_at(3);
+
+        assert!(matches!(
+            invert(alphabet, tokens, 99),
+            Err(Error::InvalidBlock)
+        ));
+        assert!(matches!(
+            invert(alphabet, &tokens[..tokens.len() - 1], 100),
+            Err(Error::InvalidBlock)
+        ));
+        assert!(matches!(
+            invert(alphabet, &[MTF_BASE + 4], 100),
+            Err(Error::InvalidBlock)
+        ));
+        assert!(matches!(
+            invert(alphabet, &[RUN_B; 64], u64::MAX - 1),
+            Err(Error::InvalidBlock)
+        ));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let text = "abracadabra, 日本語 ".repeat(500);
+        assert_eq!(encode(text.as_bytes(), Mode::Text, 1000), text.as_bytes());
+        assert_eq!(encode(b"", Mode::Bytes, 1000), b"");
+        assert_eq!(encode(b"x", Mode::Bytes, 1000), b"x");
+        assert_eq!(encode(&[0xaa; 3000], Mode::Bytes, 1000), [0xaa; 3000]);
+
+        let bytes = (0..10_000u32)
+            .map(|i| (i * i % 251) as u8)
+            .collect::<Vec<_>>();
+        for block_size in [1, 999, 1000, 10_000] {
+            assert_eq!(encode(&bytes, Mode::Bytes, block_size), bytes);
+        }
+    }
+}
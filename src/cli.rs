@@ -0,0 +1,328 @@
+use crate::coder::Coder;
+
+pub const USAGE: &str = "\
+usage: cchuff [command] [options] [file]...
+
+With no command files are compressed, with no file or when file is -
+standard input is read.
+
+commands:
+  compress      compress each file to <file>.cchf (default)
+  decompress    decompress each <file>.cchf
+  test          check the integrity of compressed files
+  info          show the header and block layout of compressed files
+
+options:
+  -o, --output <path>  write to <path> instead of the default name, - for stdout
+  -c, --stdout         write to standard output, keep the input files
+  -f, --force          overwrite existing output files
+  -k, --keep           keep the input files
+  -v, --verbose        report every processed file
+  -q, --quiet          suppress warnings
+      --max-code-len <n>
+                       limit codes to <n> bits, between 1 and 63
+      --context        use one code table per previous symbol context
+      --adaptive       use adaptive Huffman codes, no tables are stored
+      --rle            code runs of a repeated symbol as their length
+      --coder <name>   entropy coder, huffman (default), tans, range, lz77 or bwt
+      --gzip           write and read gzip files ending in .gz instead
+      --threads <n>    process <n> blocks at once, needs the parallel feature
+      --stats          print compression statistics on stderr
+      --json           print compression statistics as JSON lines on stderr
+  -h, --help           show this message";
+
+/// Suffix appended to compressed files.
+pub const SUFFIX: &str = ".cchf";
+/// Suffix appended to files compressed with `--gzip`.
+pub const GZIP_SUFFIX: &str = ".gz";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Compress,
+    Decompress,
+    Test,
+    Info,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub command: Command,
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub stdout: bool,
+    pub force: bool,
+    pub keep: bool,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub max_code_len: Option<u8>,
+    pub context: bool,
+    pub adaptive: bool,
+    pub rle: bool,
+    pub coder: Coder,
+    pub gzip: bool,
+    pub threads: usize,
+    pub stats: bool,
+    pub json: bool,
+}
+
+impl Config {
+    /// Parses the command line, without the program name. `Ok(None)` means
+    /// help was requested.
+    pub fn build(args: impl Iterator<Item = String>) -> Result<Option<Config>, String> {
+        let mut args = args.peekable();
+        // Like gzip, compress when no command is given
+        let command = match args.peek().map(String::as_str) {
+            Some("compress") => Command::Compress,
+            Some("decompress") => Command::Decompress,
+            Some("test") => Command::Test,
+            Some("info") => Command::Info,
+            _ => Command::Compress,
+        };
+        args.next_if(|arg| matches!(arg.as_str(), "compress" | "decompress" | "test" | "info"));
+
+        let mut config = Config {
+            command,
+            inputs: Vec::new(),
+            output: None,
+            stdout: false,
+            force: false,
+            keep: false,
+            verbose: false,
+            quiet: false,
+            max_code_len: None,
+            context: false,
+            adaptive: false,
+            rle: false,
+            coder: Coder::Huffman,
+            gzip: false,
+            threads: 1,
+            stats: false,
+            json: false,
+        };
+        let mut only_inputs = false;
+
+        while let Some(arg) = args.next() {
+            if only_inputs || arg == "-" || !arg.starts_with('-') {
+                config.inputs.push(arg);
+                continue;
+            }
+
+            if let Some(long) = arg.strip_prefix("--") {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (long, None),
+                };
+                match name {
+                    "" => only_inputs = true,
+                    "output" => config.output = Some(value_for(name, value, &mut args)?),
+                    "stdout" => config.stdout = true,
+                    "force" => config.force = true,
+                    "keep" => config.keep = true,
+                    "verbose" => config.verbose = true,
+                    "quiet" => config.quiet = true,
+                    "max-code-len" => {
+                        let value = value_for(name, value, &mut args)?;
+                        let max_code_len = value
+                            .parse()
+                            .ok()
+                            .filter(|len| (1..=63).contains(len))
+                            .ok_or_else(|| format!("invalid code length limit '{value}'"))?;
+                        config.max_code_len = Some(max_code_len);
+                    }
+                    "context" => config.context = true,
+                    "adaptive" => config.adaptive = true,
+                    "rle" => config.rle = true,
+                    "coder" => {
+                        let value = value_for(name, value, &mut args)?;
+                        config.coder = Coder::from_name(&value)
+                            .ok_or_else(|| format!("unknown coder '{value}'"))?;
+                    }
+                    "gzip" => config.gzip = true,
+                    "threads" => {
+                        let value = value_for(name, value, &mut args)?;
+                        config.threads = value
+                            .parse()
+                            .ok()
+                            .filter(|&threads| threads > 0)
+                            .ok_or_else(|| format!("invalid number of threads '{value}'"))?;
+                    }
+                    "stats" => config.stats = true,
+                    "json" => config.json = true,
+                    "help" => return Ok(None),
+                    _ => return Err(format!("unknown option '--{name}'")),
+                }
+                continue;
+            }
+
+            // Short flags may be grouped as in `-kv`, `-o` takes the rest of
+            // the group or the next argument as its value
+            let flags = &arg[1..];
+            for (i, flag) in flags.char_indices() {
+                match flag {
+                    'o' => {
+                        let rest = &flags[i + 1..];
+                        let value = (!rest.is_empty()).then(|| rest.to_string());
+                        config.output = Some(value_for("output", value, &mut args)?);
+                        break;
+                    }
+                    'c' => config.stdout = true,
+                    'f' => config.force = true,
+                    'k' => config.keep = true,
+                    'v' => config.verbose = true,
+                    'q' => config.quiet = true,
+                    'h' => return Ok(None),
+                    _ => return Err(format!("unknown option '-{flag}'")),
+                }
+            }
+        }
+
+        if config.inputs.is_empty() {
+            config.inputs.push("-".to_string());
+        }
+        if config.output.is_some() && config.inputs.len() > 1 {
+            return Err("--output needs a single input file".to_string());
+        }
+        if config.output.is_some() && config.stdout {
+            return Err("--output and --stdout cannot be used together".to_string());
+        }
+        if config.context && config.adaptive {
+            return Err("--context and --adaptive cannot be used together".to_string());
+        }
+        if config.rle && config.adaptive {
+            return Err("--rle and --adaptive cannot be used together".to_string());
+        }
+        if !config.coder.huffman_tables() && config.max_code_len.is_some() {
+            return Err("--max-code-len needs the huffman, lz77 or bwt coder".to_string());
+        }
+        if config.coder != Coder::Huffman {
+            let huffman_only = [
+                ("--context", config.context),
+                ("--adaptive", config.adaptive),
+                ("--rle", config.rle),
+            ];
+            if let Some((option, _)) = huffman_only.into_iter().find(|&(_, set)| set) {
+                return Err(format!("{option} needs the huffman coder"));
+            }
+        }
+        if config.gzip {
+            if config.command == Command::Info {
+                return Err("info does not read gzip files".to_string());
+            }
+            let cchf_only = [
+                ("--max-code-len", config.max_code_len.is_some()),
+                ("--context", config.context),
+                ("--adaptive", config.adaptive),
+                ("--rle", config.rle),
+                ("--coder", config.coder != Coder::Huffman),
+            ];
+            if let Some((option, _)) = cchf_only.into_iter().find(|&(_, set)| set) {
+                return Err(format!("{option} cannot be used with --gzip"));
+            }
+        }
+
+        Ok(Some(config))
+    }
+}
+
+fn value_for(
+    name: &str,
+    value: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, String> {
+    value
+        .or_else(|| args.next())
+        .ok_or_else(|| format!("option '--{name}' needs a value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(args: &str) -> Result<Option<Config>, String> {
+        Config::build(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn test_commands() {
+        let config = build("").unwrap().unwrap();
+        assert_eq!(config.command, Command::Compress);
+        assert_eq!(config.inputs, ["-"]);
+
+        let config = build("decompress a.cchf b.cchf").unwrap().unwrap();
+        assert_eq!(config.command, Command::Decompress);
+        assert_eq!(config.inputs, ["a.cchf", "b.cchf"]);
+
+        // A file named like a command only counts as one first
+        let config = build("test info").unwrap().unwrap();
+        assert_eq!(config.command, Command::Test);
+        assert_eq!(config.inputs, ["info"]);
+    }
+
+    #[test]
+    fn test_options() {
+        let config = build("-kvf --coder=bwt --max-code-len 12 --threads 4 a")
+            .unwrap()
+            .unwrap();
+        assert!(config.keep && config.verbose && config.force);
+        assert!(!config.quiet && !config.stdout);
+        assert_eq!(config.coder, Coder::Bwt);
+        assert_eq!(config.max_code_len, Some(12));
+        assert_eq!(config.threads, 4);
+
+        // `-o` takes the rest of its group or the next argument
+        let config = build("-ko out a").unwrap().unwrap();
+        assert_eq!(config.output.as_deref(), Some("out"));
+        assert_eq!(config.inputs, ["a"]);
+        let config = build("-kout a").unwrap().unwrap();
+        assert_eq!(config.output.as_deref(), Some("ut"));
+
+        let config = build("-- -c -").unwrap().unwrap();
+        assert!(!config.stdout);
+        assert_eq!(config.inputs, ["-c", "-"]);
+
+        assert!(build("-h").unwrap().is_none());
+        assert!(build("a --help").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(build("-x").is_err());
+        assert!(build("--unknown").is_err());
+        assert!(build("--output").is_err());
+        assert!(build("--coder zip").is_err());
+        assert!(build("--max-code-len 0").is_err());
+        assert!(build("--max-code-len 64").is_err());
+        assert!(build("--threads 0").is_err());
+    }
+
+    #[test]
+    fn test_exclusive_options() {
+        for args in [
+            "-o out a b",
+            "-o out -c a",
+            "--context --adaptive",
+            "--rle --adaptive",
+            "--coder tans --max-code-len 8",
+            "--coder range --max-code-len 8",
+            "--coder lz77 --context",
+            "--coder bwt --rle",
+            "--coder tans --adaptive",
+            "info --gzip",
+            "--gzip --max-code-len 8",
+            "--gzip --context",
+            "--gzip --coder lz77",
+        ] {
+            assert!(build(args).is_err(), "{args}");
+        }
+
+        for args in [
+            "-o out a",
+            "--context --rle",
+            "--coder lz77 --max-code-len 8",
+            "--coder bwt --max-code-len 8",
+            "decompress --gzip a.gz",
+        ] {
+            assert!(build(args).is_ok(), "{args}");
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use bitvec::prelude::*;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use crate::{
+    decoder::{reader::BitReader, Output},
+    error::Error,
+};
+
+mod bwt;
+mod counts;
+mod huffman;
+mod lz77;
+mod range;
+mod tans;
+
+pub use bwt::Bwt;
+pub use huffman::Huffman;
+pub use lz77::Lz77;
+pub use range::Range;
+pub use tans::Tans;
+
+/// Entropy coder of the blocks of a file, its id is stored in the header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Coder {
+    /// Canonical Huffman codes, the only coder before version 6.
+    #[default]
+    Huffman,
+    /// Table based asymmetric numeral systems, which spend fractions of a
+    /// bit on frequent symbols.
+    Tans,
+    /// Static range coding, the best ratio at the lowest speed.
+    Range,
+    /// LZ77 matches with Huffman codes for literals, lengths and distances,
+    /// for content with repeated strings.
+    Lz77,
+    /// Burrows-Wheeler transform and move-to-front before Huffman codes,
+    /// the best ratio on text.
+    Bwt,
+}
+
+impl Coder {
+    pub fn id(self) -> u8 {
+        match self {
+            Coder::Huffman => 0,
+            Coder::Tans => 1,
+            Coder::Range => 2,
+            Coder::Lz77 => 3,
+            Coder::Bwt => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Coder> {
+        match id {
+            0 => Some(Coder::Huffman),
+            1 => Some(Coder::Tans),
+            2 => Some(Coder::Range),
+            3 => Some(Coder::Lz77),
+            4 => Some(Coder::Bwt),
+            _ => None,
+        }
+    }
+
+    /// Name of the coder on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Coder::Huffman => "huffman",
+            Coder::Tans => "tans",
+            Coder::Range => "range",
+            Coder::Lz77 => "lz77",
+            Coder::Bwt => "bwt",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Coder> {
+        [
+            Coder::Huffman,
+            Coder::Tans,
+            Coder::Range,
+            Coder::Lz77,
+            Coder::Bwt,
+        ]
+        .into_iter()
+        .find(|coder| coder.name() == name)
+    }
+
+    /// Whether the coder codes with Huffman tables, which the code length
+    /// limit applies to.
+    pub fn huffman_tables(self) -> bool {
+        matches!(self, Coder::Huffman | Coder::Lz77 | Coder::Bwt)
+    }
+}
+
+/// Table built for a block, with its serialized form and the size of the
+/// coded symbols in bits.
+pub type Built<T> = (T, BitVec<u8, Msb0>, u64);
+
+/// Codes the symbols of a block with a table built from their frequencies.
+///
+/// The table is serialized with the block, or left out when the block
+/// reuses the table of the previous one. Payloads start byte aligned and
+/// their size in bits is stored, the coder reads no more than that.
+pub(crate) trait EntropyCoder: Sync {
+    /// Table the encoder codes a block with.
+    type Table: Send + Sync;
+    /// Table the decoder decodes a block with.
+    type DecodeTable: Send + Sync;
+
+    /// Turns the symbols of a block into the symbols the coder codes, the
+    /// ones the other methods get. They are the block symbols by default.
+    fn prepare<'a>(&self, symbols: &'a [u32]) -> Cow<'a, [u32]> {
+        Cow::Borrowed(symbols)
+    }
+
+    /// Builds the table for `symbols`, whose frequencies are `freq_table`.
+    fn build(
+        &self,
+        symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Result<Built<Self::Table>, Error>;
+
+    /// Size in bits of `symbols` coded with the table of an earlier block,
+    /// `None` when the table cannot code one of them.
+    fn cost(
+        &self,
+        table: &Self::Table,
+        symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Option<u64>;
+
+    /// Encodes `symbols` into payload bytes, the last byte padded with
+    /// zeros. Returns the bytes and their size in bits.
+    fn encode(&self, table: &Self::Table, symbols: &[u32]) -> (Vec<u8>, u64);
+
+    /// Parses a table serialized by `build`, adding its symbols to
+    /// `symbols`.
+    fn read_table(
+        &self,
+        table_bits: &BitSlice<u8, Msb0>,
+        symbols: &mut BTreeSet<u32>,
+    ) -> Result<Self::DecodeTable, Error>;
+
+    /// Decodes `total_bits` of payload into `out`, undoing `prepare`.
+    fn decode<R: Read, W: Write>(
+        &self,
+        table: &Self::DecodeTable,
+        reader: &mut BitReader<R>,
+        total_bits: u64,
+        out: &mut Output<W>,
+    ) -> Result<(), Error>;
+}
@@ -0,0 +1,185 @@
+use bitvec::prelude::*;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use super::{Built, EntropyCoder, Huffman};
+use crate::{
+    bwt,
+    decoder::{self, reader::BitReader, Output},
+    encoder::huffman::{push_gamma, CodeSet},
+    error::Error,
+    shared::TOKEN_BASE,
+};
+
+/// Burrows-Wheeler transform, move-to-front and zero run-length coding
+/// before Huffman codes, as in bzip2.
+///
+/// Sorting the rotations of a block groups symbols by what follows them,
+/// so the transformed block is mostly runs of a few symbols. Move-to-front
+/// turns those into small positions and runs of zeros, which are coded as
+/// their length. The tokens are coded with a single Huffman table per
+/// block.
+///
+/// The table of a block is the number of distinct symbols the move-to-front
+/// list starts with, then the gaps between them in ascending order, both
+/// Elias gamma coded, then the Huffman table as with the Huffman coder.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bwt {
+    /// Longest code a table may hold.
+    pub max_code_len: Option<u8>,
+}
+
+/// Encoding table of a block.
+pub struct BwtTable {
+    /// Distinct symbols of the block, in ascending order.
+    alphabet: Vec<u32>,
+    codes: CodeSet,
+}
+
+/// Decoding table of a block.
+pub struct BwtDecodeTable {
+    alphabet: Vec<u32>,
+    codes: decoder::CodeSet,
+}
+
+impl Bwt {
+    fn huffman(&self) -> Huffman {
+        Huffman {
+            max_code_len: self.max_code_len,
+            context: false,
+            rle: false,
+        }
+    }
+}
+
+impl EntropyCoder for Bwt {
+    type Table = BwtTable;
+    type DecodeTable = BwtDecodeTable;
+
+    /// The distinct symbols of the block come first, each once, then the
+    /// tokens.
+    fn prepare<'a>(&self, symbols: &'a [u32]) -> Cow<'a, [u32]> {
+        Cow::Owned(bwt::transform(symbols))
+    }
+
+    fn build(
+        &self,
+        tokens: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Result<Built<BwtTable>, Error> {
+        let (alphabet, tokens, freq_table) = split(tokens, freq_table);
+        let (codes, table_bits, total_bits) = self.huffman().build(tokens, &freq_table)?;
+
+        let mut bitvec = BitVec::new();
+        push_gamma(&mut bitvec, alphabet.len() as u64 + 1);
+        let mut next = 0u64;
+        for &symbol in alphabet {
+            push_gamma(&mut bitvec, u64::from(symbol) - next + 1);
+            next = u64::from(symbol) + 1;
+        }
+        bitvec.extend_from_bitslice(&table_bits);
+
+        let table = BwtTable {
+            alphabet: alphabet.to_vec(),
+            codes,
+        };
+        Ok((table, bitvec, total_bits))
+    }
+
+    fn cost(
+        &self,
+        table: &BwtTable,
+        tokens: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Option<u64> {
+        // The positions of the tokens only hold for the same list
+        let (alphabet, tokens, freq_table) = split(tokens, freq_table);
+        if alphabet != table.alphabet {
+            return None;
+        }
+        self.huffman().cost(&table.codes, tokens, &freq_table)
+    }
+
+    fn encode(&self, table: &BwtTable, tokens: &[u32]) -> (Vec<u8>, u64) {
+        self.huffman()
+            .encode(&table.codes, &tokens[table.alphabet.len()..])
+    }
+
+    fn read_table(
+        &self,
+        table_bits: &BitSlice<u8, Msb0>,
+        symbols: &mut BTreeSet<u32>,
+    ) -> Result<BwtDecodeTable, Error> {
+        let mut iter = table_bits.iter().by_vals();
+        let len = decoder::read_gamma(&mut iter).ok_or(Error::InvalidTree)? - 1;
+        let mut alphabet = Vec::new();
+        let mut next = 0u64;
+        for _ in 0..len {
+            let symbol = decoder::read_gamma(&mut iter)
+                .and_then(|gap| next.checked_add(gap - 1))
+                .filter(|&symbol| symbol < u64::from(TOKEN_BASE))
+                .ok_or(Error::InvalidTree)?;
+            alphabet.push(symbol as u32);
+            next = symbol + 1;
+        }
+        symbols.extend(&alphabet);
+
+        // The tokens are not content
+        let rest = &table_bits[table_bits.len() - iter.len()..];
+        let codes = self.huffman().read_table(rest, &mut BTreeSet::new())?;
+
+        Ok(BwtDecodeTable { alphabet, codes })
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        table: &BwtDecodeTable,
+        reader: &mut BitReader<R>,
+        total_bits: u64,
+        out: &mut Output<W>,
+    ) -> Result<(), Error> {
+        // The whole block is needed to undo the transform, its decoded size
+        // bounds how much of it is held
+        let expected = out.expected().ok_or(Error::InvalidBlock)?;
+
+        let mut tokens = Vec::new();
+        let mut remaining = total_bits;
+        while remaining > 0 {
+            // Every token stands for at least one symbol and the end marker
+            // is one more
+            if tokens.len() as u64 > expected {
+                return Err(Error::InvalidBlock);
+            }
+            tokens.push(table.codes.read_symbol(reader, &mut remaining)?);
+            // Past the end the reader yields zeros, so only the stored
+            // size would stop the tokens
+            if reader.is_past_end() {
+                return Err(Error::Truncated);
+            }
+        }
+
+        for symbol in bwt::invert(&table.alphabet, &tokens, expected)? {
+            out.push(symbol)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits the prepared `tokens` of a block into its distinct symbols and the
+/// tokens after them, with the frequencies of those tokens.
+fn split<'a>(
+    tokens: &'a [u32],
+    freq_table: &BTreeMap<u32, u32>,
+) -> (&'a [u32], &'a [u32], BTreeMap<u32, u32>) {
+    let (alphabet, tokens) = tokens.split_at(freq_table.range(..TOKEN_BASE).count());
+    let freq_table = freq_table
+        .range(TOKEN_BASE..)
+        .map(|(&token, &freq)| (token, freq))
+        .collect();
+
+    (alphabet, tokens, freq_table)
+}
@@ -0,0 +1,212 @@
+use bitvec::prelude::*;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap},
+    ops::RangeInclusive,
+};
+
+use crate::{decoder, encoder::huffman::push_gamma, error::Error};
+
+/// Number of bits storing the log of the count total in a serialized table.
+const LOG_BITS: usize = 5;
+
+/// Log of the count total for a block of `len` symbols with `distinct` of
+/// them. Blocks shorter than `1 << default` get a smaller total, but every
+/// symbol keeps room for at least two.
+pub fn scale_log(distinct: usize, len: usize, default: u32, logs: RangeInclusive<u32>) -> u32 {
+    let needed = usize::BITS - distinct.saturating_sub(1).leading_zeros() + 1;
+    let wanted = default.min(usize::BITS - len.leading_zeros());
+
+    wanted.max(needed).clamp(*logs.start(), *logs.end())
+}
+
+/// Change in coded size of a symbol when its count moves by one.
+struct Delta {
+    bits: f64,
+    symbol: u32,
+}
+
+impl PartialEq for Delta {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Delta {}
+
+impl PartialOrd for Delta {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delta {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bits
+            .total_cmp(&other.bits)
+            .then(other.symbol.cmp(&self.symbol))
+    }
+}
+
+/// Scales `freq_table` to counts summing to `1 << log`, every symbol keeping
+/// a count of at least one.
+///
+/// Counts start rounded down, then the difference to the total is handed
+/// out or taken back one at a time where that changes the coded size the
+/// least.
+pub fn normalize(freq_table: &BTreeMap<u32, u32>, log: u32) -> BTreeMap<u32, u32> {
+    let target = 1u64 << log;
+
+    // A lone symbol with every count would cost no bits at all, and the
+    // decoder bounds the symbols of a payload by its size. A symbol that
+    // does not occur keeps one count.
+    if freq_table.len() == 1 {
+        let &symbol = freq_table.keys().next().unwrap();
+        let unused = u32::from(symbol == 0);
+        return BTreeMap::from([(symbol, target as u32 - 1), (unused, 1)]);
+    }
+
+    let total = freq_table
+        .values()
+        .map(|&freq| u64::from(freq))
+        .sum::<u64>();
+
+    let mut counts = freq_table
+        .iter()
+        .map(|(&symbol, &freq)| {
+            let count = (u64::from(freq) * target / total).max(1);
+            (symbol, count as u32)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let sum = counts.values().map(|&count| u64::from(count)).sum::<u64>();
+
+    // Saving from giving a symbol one more, or cost of taking one away
+    let delta = |symbol: u32, count: u32, grow: bool| {
+        let freq = f64::from(freq_table[&symbol]);
+        let (from, to) = if grow {
+            (count, count + 1)
+        } else {
+            (count - 1, count)
+        };
+        let bits = freq * (f64::from(to) / f64::from(from)).log2();
+        Delta {
+            bits: if grow { bits } else { -bits },
+            symbol,
+        }
+    };
+
+    let grow = sum < target;
+    let mut heap = counts
+        .iter()
+        .filter(|&(_, &count)| grow || count > 1)
+        .map(|(&symbol, &count)| delta(symbol, count, grow))
+        .collect::<BinaryHeap<_>>();
+    for _ in 0..sum.abs_diff(target) {
+        let Delta { symbol, .. } = heap.pop().expect("a symbol has a count to spare");
+        let count = counts.get_mut(&symbol).unwrap();
+        if grow {
+            *count += 1;
+        } else {
+            *count -= 1;
+        }
+        if grow || *count > 1 {
+            heap.push(delta(symbol, *count, grow));
+        }
+    }
+
+    counts
+}
+
+/// Serializes `counts` summing to `1 << log`: the log, then (symbol gap,
+/// count) pairs in ascending symbol order, both Elias gamma coded.
+pub fn push_counts(bitvec: &mut BitVec<u8, Msb0>, log: u32, counts: &BTreeMap<u32, u32>) {
+    for i in (0..LOG_BITS).rev() {
+        bitvec.push((log >> i) & 1 == 1);
+    }
+
+    let mut next = 0u64;
+    for (&symbol, &count) in counts {
+        push_gamma(bitvec, u64::from(symbol) - next + 1);
+        push_gamma(bitvec, u64::from(count));
+        next = u64::from(symbol) + 1;
+    }
+}
+
+/// Reads counts written by `push_counts`, checking their log is in `logs`
+/// and that they add up to `1 << log`.
+pub fn read_counts(
+    table_bits: &BitSlice<u8, Msb0>,
+    logs: RangeInclusive<u32>,
+) -> Result<(u32, BTreeMap<u32, u32>), Error> {
+    let mut iter = table_bits.iter().by_vals();
+    if iter.len() < LOG_BITS {
+        return Err(Error::InvalidTree);
+    }
+    let log = iter
+        .by_ref()
+        .take(LOG_BITS)
+        .fold(0, |acc, b| (acc << 1) | u32::from(b));
+    if !logs.contains(&log) {
+        return Err(Error::InvalidTree);
+    }
+
+    let target = 1u64 << log;
+    let mut counts = BTreeMap::new();
+    let mut next = 0u64;
+    let mut total = 0u64;
+    while iter.len() > 0 {
+        let symbol = decoder::read_gamma(&mut iter)
+            .and_then(|gap| next.checked_add(gap - 1)?.try_into().ok())
+            .ok_or(Error::InvalidTree)?;
+        let count = decoder::read_gamma(&mut iter).ok_or(Error::InvalidTree)?;
+        total += count;
+        if total > target {
+            return Err(Error::InvalidTree);
+        }
+
+        counts.insert(symbol, count as u32);
+        next = u64::from(symbol) + 1;
+    }
+    if total != target {
+        return Err(Error::InvalidTree);
+    }
+
+    Ok((log, counts))
+}
+
+/// Count of every symbol and the sum of the counts before it, indexed by
+/// symbol. Symbols without a count have a count of 0.
+pub fn cumulative(counts: &BTreeMap<u32, u32>) -> Vec<(u32, u32)> {
+    let size = counts
+        .keys()
+        .next_back()
+        .map_or(0, |&symbol| symbol as usize + 1);
+    let mut cumulative = vec![(0, 0); size];
+    let mut start = 0;
+    for (&symbol, &count) in counts {
+        cumulative[symbol as usize] = (count, start);
+        start += count;
+    }
+
+    cumulative
+}
+
+/// Size in bits of the symbols of `freq_table` coded with counts summing to
+/// `1 << log`, as returned by `cumulative`. `None` when one of the symbols
+/// has no count.
+pub fn estimated_bits(
+    log: u32,
+    cumulative: &[(u32, u32)],
+    freq_table: &BTreeMap<u32, u32>,
+) -> Option<u64> {
+    let mut bits = 0.0;
+    for (&symbol, &freq) in freq_table {
+        let &(count, _) = cumulative.get(symbol as usize)?;
+        if count == 0 {
+            return None;
+        }
+        bits += f64::from(freq) * (f64::from(log) - f64::from(count).log2());
+    }
+
+    Some(bits.ceil() as u64)
+}
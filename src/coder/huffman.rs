@@ -0,0 +1,149 @@
+use bitvec::prelude::*;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use super::{Built, EntropyCoder};
+use crate::{
+    decoder::{self, reader::BitReader, Output},
+    encoder::{
+        huffman::{self, CodeMap, CodeSet},
+        writer,
+    },
+    error::Error,
+    rle,
+    shared::TOKEN_BASE,
+};
+
+/// Canonical Huffman codes, the table being the code length of every symbol.
+///
+/// With `context` a block holds one table per context of the previous
+/// symbol instead of a single one. With `rle` runs of a repeated symbol
+/// are coded as the symbol followed by run tokens, which share its table.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Huffman {
+    /// Longest code a table may hold.
+    pub max_code_len: Option<u8>,
+    pub context: bool,
+    pub rle: bool,
+}
+
+impl EntropyCoder for Huffman {
+    type Table = CodeSet;
+    type DecodeTable = decoder::CodeSet;
+
+    /// With `rle` a block keeps its plain symbols when the run tokens
+    /// would not make it smaller. Its table then has no run tokens, which
+    /// decodes the same way.
+    fn prepare<'a>(&self, symbols: &'a [u32]) -> Cow<'a, [u32]> {
+        if !self.rle {
+            return Cow::Borrowed(symbols);
+        }
+
+        let tokens = rle::transform(symbols);
+        let smaller = tokens.len() < symbols.len()
+            && self
+                .coded_bits(&tokens)
+                .is_some_and(|bits| self.coded_bits(symbols).is_none_or(|plain| bits < plain));
+        if smaller {
+            Cow::Owned(tokens)
+        } else {
+            Cow::Borrowed(symbols)
+        }
+    }
+
+    fn build(
+        &self,
+        symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Result<Built<CodeSet>, Error> {
+        if self.context {
+            return huffman::build_contexts(symbols, freq_table, self.max_code_len);
+        }
+
+        let (code_set, bitvec) = huffman::build(freq_table, self.max_code_len)?;
+        let total_bits = payload_bits(freq_table, &code_set.maps[0]);
+        Ok((code_set, bitvec, total_bits))
+    }
+
+    fn cost(
+        &self,
+        code_set: &CodeSet,
+        symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Option<u64> {
+        if code_set.contexts.is_empty() {
+            let code_map = &code_set.maps[0];
+            return freq_table
+                .keys()
+                .all(|&symbol| code_map.contains(symbol))
+                .then(|| payload_bits(freq_table, code_map));
+        }
+
+        // The table of a symbol depends on the one before it, so go through
+        // the symbols themselves
+        let mut bits = 0;
+        let mut table = 0;
+        for &symbol in symbols {
+            let len = code_set.maps[table].get(symbol).1;
+            if len == 0 {
+                return None;
+            }
+            bits += u64::from(len);
+            table = code_set.contexts.table(symbol);
+        }
+
+        Some(bits)
+    }
+
+    fn encode(&self, code_set: &CodeSet, symbols: &[u32]) -> (Vec<u8>, u64) {
+        writer::encode_payload(code_set, symbols)
+    }
+
+    fn read_table(
+        &self,
+        table_bits: &BitSlice<u8, Msb0>,
+        symbols: &mut BTreeSet<u32>,
+    ) -> Result<decoder::CodeSet, Error> {
+        if !self.rle {
+            return decoder::read_code_set(table_bits, self.context, self.max_code_len, symbols);
+        }
+
+        // Run tokens are not content
+        let mut tokens = BTreeSet::new();
+        let code_set =
+            decoder::read_code_set(table_bits, self.context, self.max_code_len, &mut tokens)?;
+        symbols.extend(tokens.range(..TOKEN_BASE));
+
+        Ok(code_set)
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        code_set: &decoder::CodeSet,
+        reader: &mut BitReader<R>,
+        total_bits: u64,
+        out: &mut Output<W>,
+    ) -> Result<(), Error> {
+        decoder::decode_content(reader, code_set, total_bits, self.rle, out)
+    }
+}
+
+impl Huffman {
+    /// Size in bits of `symbols` coded with their own table, the table
+    /// included. `None` when no table can be built for them.
+    fn coded_bits(&self, symbols: &[u32]) -> Option<u64> {
+        let freq_table = huffman::count_frequency(symbols, 1);
+        let (_, bitvec, total_bits) = self.build(symbols, &freq_table).ok()?;
+        Some(total_bits + bitvec.len() as u64)
+    }
+}
+
+fn payload_bits(freq_table: &BTreeMap<u32, u32>, code_map: &CodeMap) -> u64 {
+    freq_table
+        .iter()
+        .map(|(&symbol, &freq)| u64::from(freq) * u64::from(code_map.get(symbol).1))
+        .sum()
+}
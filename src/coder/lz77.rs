@@ -0,0 +1,262 @@
+use bitvec::prelude::*;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use super::{Built, EntropyCoder};
+use crate::{
+    decoder::{self, reader::BitReader, CodeSet, Output},
+    encoder::{
+        huffman::{self, push_gamma, CodeMap},
+        writer::BitWriter,
+    },
+    error::Error,
+    lz77::{self, Window, DISTANCE_BASE, DISTANCE_CODES, LENGTH_BASE, LENGTH_CODES, MIN_MATCH},
+    shared::canonical_codes,
+};
+
+/// LZ77 matches with Huffman codes, as in DEFLATE.
+///
+/// Blocks are turned into literals and (length, distance) matches reaching
+/// back up to 32 KiB worth of symbols within the block. Literals and length
+/// codes share one code table, the length codes following every literal,
+/// and distance codes have a table of their own. The extra bits of a length
+/// or distance follow its code.
+///
+/// The table of a block is the number of literal and length codes, then
+/// both tables as code lengths.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lz77 {
+    /// Longest code a table may hold.
+    pub max_code_len: Option<u8>,
+}
+
+/// Encoding table of a block.
+pub struct Lz77Table {
+    literals: CodeMap,
+    /// Code of every length code.
+    lengths: CodeMap,
+    /// Code of every distance code.
+    distances: CodeMap,
+}
+
+/// Decoding table of a block.
+pub struct Lz77DecodeTable {
+    literals: CodeSet,
+    distances: CodeSet,
+}
+
+impl EntropyCoder for Lz77 {
+    type Table = Lz77Table;
+    type DecodeTable = Lz77DecodeTable;
+
+    fn prepare<'a>(&self, symbols: &'a [u32]) -> Cow<'a, [u32]> {
+        Cow::Owned(lz77::tokenize(symbols))
+    }
+
+    fn build(
+        &self,
+        _tokens: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Result<Built<Lz77Table>, Error> {
+        let (literals, distances) = code_frequencies(freq_table);
+        let literals = huffman::code_lengths(&literals, self.max_code_len)?;
+        let distances = huffman::code_lengths(&distances, self.max_code_len)?;
+
+        let mut bitvec = BitVec::new();
+        push_gamma(&mut bitvec, literals.len() as u64 + 1);
+        huffman::push_code_table(&mut bitvec, &literals);
+        huffman::push_code_table(&mut bitvec, &distances);
+
+        let table = Lz77Table::new(&literals, &distances);
+        let total_bits = table.cost(freq_table).unwrap_or(0);
+
+        Ok((table, bitvec, total_bits))
+    }
+
+    fn cost(
+        &self,
+        table: &Lz77Table,
+        _tokens: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Option<u64> {
+        table.cost(freq_table)
+    }
+
+    fn encode(&self, table: &Lz77Table, tokens: &[u32]) -> (Vec<u8>, u64) {
+        let mut pending = BitWriter::new();
+        for &token in tokens {
+            let (code, len) = table.code(token);
+            pending.put(code, u32::from(len));
+            if token >= LENGTH_BASE {
+                let (_, extra_bits, extra) = extra(token);
+                pending.put(extra, extra_bits);
+            }
+        }
+
+        let bits = pending.len();
+        (pending.finish(), bits)
+    }
+
+    fn read_table(
+        &self,
+        table_bits: &BitSlice<u8, Msb0>,
+        symbols: &mut BTreeSet<u32>,
+    ) -> Result<Lz77DecodeTable, Error> {
+        let mut iter = table_bits.iter().by_vals();
+        let entries = decoder::read_gamma(&mut iter).ok_or(Error::InvalidTree)? - 1;
+        let literals = decoder::read_code_lengths(&mut iter, Some(entries))?;
+        let distances = decoder::read_code_lengths(&mut iter, None)?;
+        symbols.extend(literals.range(..LENGTH_BASE).map(|(&symbol, _)| symbol));
+
+        Ok(Lz77DecodeTable {
+            literals: CodeSet::from_lengths(&literals, self.max_code_len)?,
+            distances: CodeSet::from_lengths(&distances, self.max_code_len)?,
+        })
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        table: &Lz77DecodeTable,
+        reader: &mut BitReader<R>,
+        total_bits: u64,
+        out: &mut Output<W>,
+    ) -> Result<(), Error> {
+        let mut remaining = total_bits;
+        let mut window = Window::new();
+
+        while remaining > 0 {
+            let symbol = table.literals.read_symbol(reader, &mut remaining)?;
+            if symbol < LENGTH_BASE {
+                out.push(symbol)?;
+                window.push(symbol);
+                continue;
+            }
+
+            let len = read_extra(reader, &LENGTH_CODES, symbol - LENGTH_BASE, &mut remaining)?;
+            let code = table.distances.read_symbol(reader, &mut remaining)?;
+            let dist = read_extra(reader, &DISTANCE_CODES, code, &mut remaining)?;
+            for _ in 0..len {
+                let symbol = window.get(dist).ok_or(Error::InvalidBlock)?;
+                out.push(symbol)?;
+                window.push(symbol);
+            }
+        }
+
+        if reader.is_past_end() {
+            return Err(Error::Truncated);
+        }
+
+        Ok(())
+    }
+}
+
+impl Lz77Table {
+    fn new(literals: &BTreeMap<u32, u8>, distances: &BTreeMap<u32, u8>) -> Self {
+        let codes = canonical_codes(literals);
+        let lengths = codes
+            .range(LENGTH_BASE..)
+            .map(|(&symbol, &code)| (symbol - LENGTH_BASE, code))
+            .collect();
+        let literals = codes
+            .range(..LENGTH_BASE)
+            .map(|(&symbol, &code)| (symbol, code))
+            .collect();
+
+        Lz77Table {
+            literals: CodeMap::from_codes(&literals),
+            lengths: CodeMap::from_codes(&lengths),
+            distances: CodeMap::from_codes(&canonical_codes(distances)),
+        }
+    }
+
+    /// Code of the literal or of the length or distance code of `token`.
+    #[inline]
+    fn code(&self, token: u32) -> (u64, u8) {
+        if token < LENGTH_BASE {
+            self.literals.get(token)
+        } else if token < DISTANCE_BASE {
+            self.lengths.get(extra(token).0 as u32)
+        } else {
+            self.distances.get(extra(token).0 as u32)
+        }
+    }
+
+    /// Size in bits of the tokens of `freq_table` with their extra bits,
+    /// `None` when one of them has no code.
+    fn cost(&self, freq_table: &BTreeMap<u32, u32>) -> Option<u64> {
+        let mut bits = 0;
+        for (&token, &freq) in freq_table {
+            let len = self.code(token).1;
+            if len == 0 {
+                return None;
+            }
+            let extra_bits = if token >= LENGTH_BASE {
+                extra(token).1
+            } else {
+                0
+            };
+            bits += u64::from(freq) * u64::from(u32::from(len) + extra_bits);
+        }
+
+        Some(bits)
+    }
+}
+
+/// Length or distance code of a match token, with its extra bits and their
+/// value.
+#[inline]
+fn extra(token: u32) -> (usize, u32, u64) {
+    if token < DISTANCE_BASE {
+        lz77::code_of(&LENGTH_CODES, (token - LENGTH_BASE) as usize + MIN_MATCH)
+    } else {
+        lz77::code_of(&DISTANCE_CODES, (token - DISTANCE_BASE) as usize + 1)
+    }
+}
+
+/// Frequencies of the literals and length codes, the length codes counted
+/// from `LENGTH_BASE`, and of the distance codes.
+fn code_frequencies(freq_table: &BTreeMap<u32, u32>) -> (BTreeMap<u32, u32>, BTreeMap<u32, u32>) {
+    let mut literals = BTreeMap::new();
+    let mut distances = BTreeMap::new();
+    for (&token, &freq) in freq_table {
+        if token < LENGTH_BASE {
+            literals.insert(token, freq);
+        } else if token < DISTANCE_BASE {
+            *literals
+                .entry(LENGTH_BASE + extra(token).0 as u32)
+                .or_insert(0) += freq;
+        } else {
+            *distances.entry(extra(token).0 as u32).or_insert(0) += freq;
+        }
+    }
+
+    (literals, distances)
+}
+
+/// Reads the extra bits after code `code` of `codes`, returning the length
+/// or distance they give.
+#[inline]
+fn read_extra<R: Read>(
+    reader: &mut BitReader<R>,
+    codes: &[(u16, u8)],
+    code: u32,
+    remaining: &mut u64,
+) -> Result<usize, Error> {
+    let &(base, extra_bits) = codes.get(code as usize).ok_or(Error::InvalidTree)?;
+    if extra_bits == 0 {
+        return Ok(usize::from(base));
+    }
+    if u64::from(extra_bits) > *remaining {
+        return Err(Error::Truncated);
+    }
+
+    reader.refill()?;
+    let extra = reader.peek(u32::from(extra_bits)) as usize;
+    reader.consume(u32::from(extra_bits));
+    *remaining -= u64::from(extra_bits);
+
+    Ok(usize::from(base) + extra)
+}
@@ -0,0 +1,397 @@
+use bitvec::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use super::{counts, Built, EntropyCoder};
+use crate::{
+    decoder::{reader::BitReader, Output},
+    error::Error,
+};
+
+/// Fewest and most bits of the count total. The range is kept above
+/// `1 << 24`, so with 22 bits every count still gets a slice of at least
+/// 4 values and every Unicode scalar value can have a count.
+const MIN_SCALE_LOG: u32 = 5;
+const MAX_SCALE_LOG: u32 = 22;
+/// Most bits of the count total tried for a block.
+const DEFAULT_SCALE_LOG: u32 = 16;
+/// The range is shifted out a byte at a time once it falls below this.
+const TOP: u32 = 1 << 24;
+/// Bytes the encoder writes past the last symbol to pin the final value.
+const FLUSH_BYTES: u64 = 5;
+
+/// Static range coder, arithmetic coding on 32-bit integers.
+///
+/// Frequencies are scaled to counts summing to `1 << scale_log` and every
+/// symbol narrows the range to the slice of its count, so symbols cost
+/// their information content up to rounding. The low end of the range is
+/// written a byte at a time as its top byte settles, with carries
+/// propagated into the bytes already held back as in LZMA.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Range;
+
+/// Encoding table of a block.
+pub struct RangeTable {
+    scale_log: u32,
+    /// Count of every symbol and the sum of the counts before it, indexed
+    /// by symbol.
+    symbols: Vec<(u32, u32)>,
+}
+
+/// Decoding table of a block.
+pub struct RangeDecodeTable {
+    scale_log: u32,
+    /// Symbol, count and sum of the counts before it, in symbol order.
+    symbols: Vec<(u32, u32, u32)>,
+    /// Position in `symbols` of every value below the count total.
+    slots: Vec<u32>,
+}
+
+impl EntropyCoder for Range {
+    type Table = RangeTable;
+    type DecodeTable = RangeDecodeTable;
+
+    fn build(
+        &self,
+        symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Result<Built<RangeTable>, Error> {
+        // A larger total gives counts closer to the frequencies but takes
+        // more bits to store, keep the total costing the least overall
+        let logs = MIN_SCALE_LOG..=MAX_SCALE_LOG;
+        let distinct = freq_table.len();
+        let smallest = counts::scale_log(distinct, symbols.len(), MIN_SCALE_LOG, logs.clone());
+        let largest = counts::scale_log(distinct, symbols.len(), DEFAULT_SCALE_LOG, logs);
+
+        let built = (smallest..=largest)
+            .map(|scale_log| {
+                let counts = counts::normalize(freq_table, scale_log);
+                let mut bitvec = BitVec::new();
+                counts::push_counts(&mut bitvec, scale_log, &counts);
+
+                let table = RangeTable {
+                    scale_log,
+                    symbols: counts::cumulative(&counts),
+                };
+                let total_bits = table.cost(freq_table).unwrap_or(0);
+                (table, bitvec, total_bits)
+            })
+            .min_by_key(|(_, bitvec, total_bits)| bitvec.len() as u64 + total_bits)
+            .unwrap();
+
+        Ok(built)
+    }
+
+    fn cost(
+        &self,
+        table: &RangeTable,
+        _symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Option<u64> {
+        table.cost(freq_table)
+    }
+
+    fn encode(&self, table: &RangeTable, symbols: &[u32]) -> (Vec<u8>, u64) {
+        let mut encoder = RangeEncoder::new(symbols.len());
+        let total = 1 << table.scale_log;
+        for &symbol in symbols {
+            let (count, start) = table.symbols[symbol as usize];
+            encoder.encode(start, count, total, table.scale_log);
+        }
+
+        let payload = encoder.finish();
+        let bits = payload.len() as u64 * 8;
+        (payload, bits)
+    }
+
+    fn read_table(
+        &self,
+        table_bits: &BitSlice<u8, Msb0>,
+        symbols: &mut BTreeSet<u32>,
+    ) -> Result<RangeDecodeTable, Error> {
+        let (scale_log, counts) = counts::read_counts(table_bits, MIN_SCALE_LOG..=MAX_SCALE_LOG)?;
+        symbols.extend(counts.keys());
+
+        let mut entries = Vec::with_capacity(counts.len());
+        let mut slots = Vec::with_capacity(1 << scale_log);
+        let mut start = 0;
+        for (i, (&symbol, &count)) in counts.iter().enumerate() {
+            entries.push((symbol, count, start));
+            slots.resize(slots.len() + count as usize, i as u32);
+            start += count;
+        }
+
+        Ok(RangeDecodeTable {
+            scale_log,
+            symbols: entries,
+            slots,
+        })
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        table: &RangeDecodeTable,
+        reader: &mut BitReader<R>,
+        total_bits: u64,
+        out: &mut Output<W>,
+    ) -> Result<(), Error> {
+        // The payload does not tell where the last symbol ends, the decoded
+        // size does
+        let expected = out.expected().ok_or(Error::InvalidBlock)?;
+        if !total_bits.is_multiple_of(8) || total_bits / 8 < FLUSH_BYTES {
+            return Err(Error::InvalidBlock);
+        }
+
+        let mut remaining = total_bits / 8;
+        let mut next_byte = || {
+            if remaining == 0 {
+                return Err(Error::Truncated);
+            }
+            remaining -= 1;
+            reader.refill()?;
+            let byte = reader.peek(8) as u32;
+            reader.consume(8);
+            Ok(byte)
+        };
+
+        // The encoder always starts with a zero byte, no carry reaches it
+        if next_byte()? != 0 {
+            return Err(Error::InvalidBlock);
+        }
+        let mut code = 0u32;
+        for _ in 1..FLUSH_BYTES {
+            code = (code << 8) | next_byte()?;
+        }
+        let mut range = u32::MAX;
+        let last = table.slots.len() as u32 - 1;
+        // Every symbol takes at least one part in the count total off the
+        // range unless it has every count, so with more symbols than this
+        // between two bytes the payload no longer bounds the symbols
+        let max_free = 8u64 << table.scale_log;
+        let mut free = 0;
+
+        while out.len() < expected {
+            if code >= range {
+                return Err(Error::InvalidBlock);
+            }
+
+            let r = range >> table.scale_log;
+            let value = (code / r).min(last);
+            let (symbol, count, start) = table.symbols[table.slots[value as usize] as usize];
+            out.push(symbol)?;
+
+            code -= r * start;
+            // The last symbol takes what rounding left at the top
+            range = if start + count > last {
+                range - r * start
+            } else {
+                r * count
+            };
+            if range >= TOP {
+                free += 1;
+                if free > max_free {
+                    return Err(Error::InvalidBlock);
+                }
+            }
+            while range < TOP {
+                code = (code << 8) | next_byte()?;
+                range <<= 8;
+                free = 0;
+            }
+        }
+
+        if remaining != 0 {
+            return Err(Error::InvalidBlock);
+        }
+        if reader.is_past_end() {
+            return Err(Error::Truncated);
+        }
+
+        Ok(())
+    }
+}
+
+impl RangeTable {
+    /// Size in bits of the symbols of `freq_table` and the bytes flushed
+    /// after them, `None` when one of the symbols has no count.
+    fn cost(&self, freq_table: &BTreeMap<u32, u32>) -> Option<u64> {
+        let bits = counts::estimated_bits(self.scale_log, &self.symbols, freq_table)?;
+        Some(bits + FLUSH_BYTES * 8)
+    }
+}
+
+/// Range encoder state. `low` has 32 bits and a carry above them. Bytes
+/// that may still receive a carry are held back, `cache` followed by 0xff
+/// bytes, `pending` of them in all.
+struct RangeEncoder {
+    out: Vec<u8>,
+    low: u64,
+    range: u32,
+    cache: u8,
+    pending: u64,
+}
+
+impl RangeEncoder {
+    fn new(symbols: usize) -> Self {
+        RangeEncoder {
+            out: Vec::with_capacity(symbols / 2),
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            pending: 1,
+        }
+    }
+
+    /// Narrows the range to the slice `[start, start + count)` of a total
+    /// of `total`, which is `1 << scale_log`.
+    #[inline]
+    fn encode(&mut self, start: u32, count: u32, total: u32, scale_log: u32) {
+        let r = self.range >> scale_log;
+        self.low += u64::from(r * start);
+        self.range = if start + count == total {
+            self.range - r * start
+        } else {
+            r * count
+        };
+
+        while self.range < TOP {
+            self.shift_low();
+            self.range <<= 8;
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xff00_0000 || self.low >= 1 << 32 {
+            let carry = (self.low >> 32) as u8;
+            self.out.push(self.cache.wrapping_add(carry));
+            for _ in 1..self.pending {
+                self.out.push(0xffu8.wrapping_add(carry));
+            }
+            self.pending = 0;
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..FLUSH_BYTES {
+            self.shift_low();
+        }
+
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{coder::Coder, decoder, encoder::Encoder, shared::Mode};
+
+    fn round_trip(input: &[u8], mode: Mode, coder: Coder) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), mode)
+            .with_coder(coder)
+            .with_block_size(1000);
+        encoder.encode(&mut &input[..]).unwrap();
+
+        decoder::decode(encoder.into_inner()).unwrap()
+    }
+
+    fn assert_matches_huffman(input: &[u8], mode: Mode) {
+        let range = round_trip(input, mode, Coder::Range);
+        assert_eq!(range, round_trip(input, mode, Coder::Huffman));
+        assert_eq!(range, input);
+    }
+
+    #[test]
+    fn test_empty_input() {
+        assert_matches_huffman(b"", Mode::Text);
+        assert_matches_huffman(b"", Mode::Bytes);
+    }
+
+    #[test]
+    fn test_single_symbol() {
+        assert_matches_huffman(b"a", Mode::Text);
+        assert_matches_huffman(&[0; 5000], Mode::Bytes);
+        assert_matches_huffman("€".repeat(5000).as_bytes(), Mode::Text);
+    }
+
+    #[test]
+    fn test_bytes_mode() {
+        let input = (0..20_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        assert_matches_huffman(&input, Mode::Bytes);
+    }
+
+    #[test]
+    fn test_text_mode() {
+        let input = "Grüße aus Köln, 東京 und 🦀! ".repeat(500);
+        assert_matches_huffman(input.as_bytes(), Mode::Text);
+    }
+
+    /// Decodes `payload` with the table serialized as `table_bits`, the
+    /// block announcing `expected` symbols.
+    fn decode(
+        table_bits: &BitSlice<u8, Msb0>,
+        payload: &[u8],
+        total_bits: u64,
+        expected: u64,
+    ) -> Result<(), Error> {
+        let table = Range.read_table(table_bits, &mut BTreeSet::new())?;
+        let mut content = Vec::new();
+        let mut out = Output::new(&mut content, Mode::Bytes, Some(expected));
+        Range.decode(&table, &mut BitReader::new(payload), total_bits, &mut out)
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        let symbols = b"abracadabra, abracadabra".repeat(100);
+        let symbols = symbols.iter().map(|&b| u32::from(b)).collect::<Vec<_>>();
+        let mut freq_table = BTreeMap::new();
+        for &symbol in &symbols {
+            *freq_table.entry(symbol).or_insert(0) += 1;
+        }
+        let (table, table_bits, _) = Range.build(&symbols, &freq_table).unwrap();
+        let (payload, bits) = Range.encode(&table, &symbols);
+        let expected = symbols.len() as u64;
+
+        assert!(decode(&table_bits, &payload, bits, expected).is_ok());
+        let half = payload.len() / 2;
+        let result = decode(&table_bits, &payload[..half], half as u64 * 8, expected);
+        assert!(matches!(
+            result,
+            Err(Error::Truncated | Error::InvalidBlock)
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_block() {
+        let symbols = (0..2000u32).map(|i| i * i % 7).collect::<Vec<_>>();
+        let mut freq_table = BTreeMap::new();
+        for &symbol in &symbols {
+            *freq_table.entry(symbol).or_insert(0) += 1;
+        }
+        let (table, table_bits, _) = Range.build(&symbols, &freq_table).unwrap();
+        let (mut payload, bits) = Range.encode(&table, &symbols);
+
+        // The carry byte the encoder starts with is always zero
+        payload[0] = 1;
+        let result = decode(&table_bits, &payload, bits, symbols.len() as u64);
+        assert!(matches!(result, Err(Error::InvalidBlock)));
+    }
+
+    #[test]
+    fn test_symbols_without_bits_stop() {
+        // A lone symbol holding the whole count total never narrows the
+        // range, so only the announced size would end the block
+        let mut table_bits = BitVec::new();
+        counts::push_counts(&mut table_bits, MIN_SCALE_LOG, &BTreeMap::from([(97, 32)]));
+        let payload = [0; FLUSH_BYTES as usize];
+
+        let result = decode(&table_bits, &payload, FLUSH_BYTES * 8, 1 << 40);
+        assert!(matches!(result, Err(Error::InvalidBlock)));
+    }
+}
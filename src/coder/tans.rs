@@ -0,0 +1,315 @@
+use bitvec::prelude::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use super::{counts, Built, EntropyCoder};
+use crate::{
+    decoder::{reader::BitReader, Output},
+    encoder::writer::BitWriter,
+    error::Error,
+};
+
+/// Fewest and most state bits a table may use. With 21 bits every Unicode
+/// scalar value can have a state of its own.
+const MIN_TABLE_LOG: u32 = 5;
+const MAX_TABLE_LOG: u32 = 21;
+/// State bits used when the block is large enough, more give counts closer
+/// to the frequencies but a larger table to store and fill.
+const DEFAULT_TABLE_LOG: u32 = 11;
+
+/// Table based asymmetric numeral systems (tANS), as in FSE.
+///
+/// Frequencies are scaled to counts summing to the number of states,
+/// `1 << table_log`, and every symbol owns as many states as its count.
+/// Coding a symbol moves from one state to another, emitting the low bits
+/// the move drops, so a symbol costs close to `log2(states / count)` bits
+/// instead of a whole number of bits as with Huffman codes.
+///
+/// The encoder goes through the symbols backwards, so the decoder reads them
+/// forwards. The payload starts with the final state of the encoder, the
+/// decoder ends on the state the encoder started from.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tans;
+
+/// Encoding table of a block.
+pub struct TansTable {
+    table_log: u32,
+    /// Count of every symbol and the position of its first state in
+    /// `states`, indexed by symbol. Symbols without states have a count of 0.
+    symbols: Vec<(u32, u32)>,
+    /// State reached from every reduced state of every symbol.
+    states: Vec<u32>,
+}
+
+/// Decoding table of a block, one entry per state.
+pub struct TansDecodeTable {
+    table_log: u32,
+    entries: Vec<DecodeEntry>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DecodeEntry {
+    symbol: u32,
+    /// Number of bits read to reach the next state.
+    bits: u32,
+    /// Next state before adding the bits read.
+    base: u32,
+}
+
+impl EntropyCoder for Tans {
+    type Table = TansTable;
+    type DecodeTable = TansDecodeTable;
+
+    fn build(
+        &self,
+        symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Result<Built<TansTable>, Error> {
+        let table_log = counts::scale_log(
+            freq_table.len(),
+            symbols.len(),
+            DEFAULT_TABLE_LOG,
+            MIN_TABLE_LOG..=MAX_TABLE_LOG,
+        );
+        let counts = counts::normalize(freq_table, table_log);
+        let mut bitvec = BitVec::new();
+        counts::push_counts(&mut bitvec, table_log, &counts);
+
+        let table = TansTable::new(table_log, &counts);
+        let total_bits = table.cost(freq_table).unwrap_or(0);
+
+        Ok((table, bitvec, total_bits))
+    }
+
+    fn cost(
+        &self,
+        table: &TansTable,
+        _symbols: &[u32],
+        freq_table: &BTreeMap<u32, u32>,
+    ) -> Option<u64> {
+        table.cost(freq_table)
+    }
+
+    fn encode(&self, table: &TansTable, symbols: &[u32]) -> (Vec<u8>, u64) {
+        let states = 1u32 << table.table_log;
+        let mut state = states;
+        let mut emitted = Vec::with_capacity(symbols.len());
+
+        for &symbol in symbols.iter().rev() {
+            let (count, first) = table.symbols[symbol as usize];
+            // Drop low bits until the state is in [count, 2 * count), the
+            // range the symbol moves from
+            let mut bits = count.leading_zeros() - state.leading_zeros();
+            if state >> bits < count {
+                bits -= 1;
+            }
+            emitted.push((state & ((1 << bits) - 1), bits));
+            state = table.states[(first + (state >> bits) - count) as usize];
+        }
+
+        let mut pending = BitWriter::new();
+        pending.put(u64::from(state - states), table.table_log);
+        for &(value, bits) in emitted.iter().rev() {
+            pending.put(u64::from(value), bits);
+        }
+
+        let bits = pending.len();
+        (pending.finish(), bits)
+    }
+
+    fn read_table(
+        &self,
+        table_bits: &BitSlice<u8, Msb0>,
+        symbols: &mut BTreeSet<u32>,
+    ) -> Result<TansDecodeTable, Error> {
+        let (table_log, counts) = counts::read_counts(table_bits, MIN_TABLE_LOG..=MAX_TABLE_LOG)?;
+        symbols.extend(counts.keys());
+        Ok(TansDecodeTable::new(table_log, &counts))
+    }
+
+    fn decode<R: Read, W: Write>(
+        &self,
+        table: &TansDecodeTable,
+        reader: &mut BitReader<R>,
+        total_bits: u64,
+        out: &mut Output<W>,
+    ) -> Result<(), Error> {
+        // Symbols can take no bits at all, so the decoded size tells when
+        // to stop
+        let expected = out.expected().ok_or(Error::InvalidBlock)?;
+        if total_bits < u64::from(table.table_log) {
+            return Err(Error::Truncated);
+        }
+
+        reader.refill()?;
+        let mut state = reader.peek(table.table_log) as usize;
+        reader.consume(table.table_log);
+        let mut remaining = total_bits - u64::from(table.table_log);
+        // A symbol reading no bits moves to a lower state unless it has
+        // every count, so a longer streak than there are states means the
+        // payload no longer bounds the symbols
+        let mut free = 0;
+
+        while out.len() < expected {
+            let entry = table.entries[state];
+            out.push(entry.symbol)?;
+
+            if u64::from(entry.bits) > remaining {
+                return Err(Error::Truncated);
+            }
+            let mut low = 0;
+            if entry.bits > 0 {
+                reader.refill()?;
+                low = reader.peek(entry.bits) as u32;
+                reader.consume(entry.bits);
+                remaining -= u64::from(entry.bits);
+                free = 0;
+            } else {
+                free += 1;
+                if free > table.entries.len() {
+                    return Err(Error::InvalidBlock);
+                }
+            }
+            state = (entry.base + low) as usize;
+        }
+
+        if reader.is_past_end() {
+            return Err(Error::Truncated);
+        }
+        // The encoder starts from the first state with every bit accounted
+        // for, anything else means the payload does not match its table
+        if state != 0 || remaining != 0 {
+            return Err(Error::InvalidBlock);
+        }
+
+        Ok(())
+    }
+}
+
+impl TansTable {
+    fn new(table_log: u32, counts: &BTreeMap<u32, u32>) -> Self {
+        let symbols = counts::cumulative(counts);
+
+        // The n-th state of a symbol in the spread table is reached from
+        // reduced state `count + n`
+        let mut states = vec![0; 1 << table_log];
+        let mut next = symbols.iter().map(|&(count, _)| count).collect::<Vec<_>>();
+        for (state, symbol) in spread(table_log, counts).into_iter().enumerate() {
+            let (count, first) = symbols[symbol as usize];
+            let reduced = &mut next[symbol as usize];
+            states[(first + *reduced - count) as usize] = state as u32 + (1 << table_log);
+            *reduced += 1;
+        }
+
+        TansTable {
+            table_log,
+            symbols,
+            states,
+        }
+    }
+
+    /// Size in bits of the symbols of `freq_table` and the final state,
+    /// `None` when one of the symbols has no state.
+    fn cost(&self, freq_table: &BTreeMap<u32, u32>) -> Option<u64> {
+        let bits = counts::estimated_bits(self.table_log, &self.symbols, freq_table)?;
+        Some(bits + u64::from(self.table_log))
+    }
+}
+
+impl TansDecodeTable {
+    fn new(table_log: u32, counts: &BTreeMap<u32, u32>) -> Self {
+        let mut next = counts.clone();
+        let entries = spread(table_log, counts)
+            .into_iter()
+            .map(|symbol| {
+                let reduced = next.get_mut(&symbol).unwrap();
+                let x = *reduced;
+                *reduced += 1;
+
+                // Read enough bits to bring the state back to
+                // [states, 2 * states)
+                let bits = table_log - (31 - x.leading_zeros());
+                DecodeEntry {
+                    symbol,
+                    bits,
+                    base: (x << bits) - (1 << table_log),
+                }
+            })
+            .collect();
+
+        TansDecodeTable { table_log, entries }
+    }
+}
+
+/// Spreads the states over the symbols, each symbol getting as many states
+/// as its count scattered over the table, so its states are found at every
+/// size.
+fn spread(table_log: u32, counts: &BTreeMap<u32, u32>) -> Vec<u32> {
+    let states = 1usize << table_log;
+    let mask = states - 1;
+    let step = (states >> 1) + (states >> 3) + 3;
+
+    let mut table = vec![0; states];
+    let mut position = 0;
+    for (&symbol, &count) in counts {
+        for _ in 0..count {
+            table[position] = symbol;
+            position = (position + step) & mask;
+        }
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::Mode;
+
+    /// Decodes `payload` with the table serialized as `table_bits`, the
+    /// block announcing `expected` symbols.
+    fn decode(
+        table_bits: &BitSlice<u8, Msb0>,
+        payload: &[u8],
+        total_bits: u64,
+        expected: u64,
+    ) -> Result<(), Error> {
+        let table = Tans.read_table(table_bits, &mut BTreeSet::new())?;
+        let mut content = Vec::new();
+        let mut out = Output::new(&mut content, Mode::Bytes, Some(expected));
+        Tans.decode(&table, &mut BitReader::new(payload), total_bits, &mut out)
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        let symbols = b"abracadabra, abracadabra".repeat(100);
+        let symbols = symbols.iter().map(|&b| u32::from(b)).collect::<Vec<_>>();
+        let mut freq_table = BTreeMap::new();
+        for &symbol in &symbols {
+            *freq_table.entry(symbol).or_insert(0) += 1;
+        }
+        let (table, table_bits, _) = Tans.build(&symbols, &freq_table).unwrap();
+        let (payload, bits) = Tans.encode(&table, &symbols);
+        let expected = symbols.len() as u64;
+
+        assert!(decode(&table_bits, &payload, bits, expected).is_ok());
+        let result = decode(&table_bits, &payload[..payload.len() / 2], bits, expected);
+        assert!(matches!(
+            result,
+            Err(Error::Truncated | Error::InvalidBlock)
+        ));
+    }
+
+    #[test]
+    fn test_symbols_without_bits_stop() {
+        // A lone symbol holding every state reads no bits at all, so only
+        // the announced size would end the block
+        let mut table_bits = BitVec::new();
+        counts::push_counts(&mut table_bits, MIN_TABLE_LOG, &BTreeMap::from([(97, 32)]));
+
+        let result = decode(&table_bits, &[0], u64::from(MIN_TABLE_LOG), 1 << 40);
+        assert!(matches!(result, Err(Error::InvalidBlock)));
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+/// Most contexts with a code table of their own in a block.
+pub const MAX_CONTEXTS: usize = 255;
+
+/// Symbols below this are looked up in a flat array.
+const DENSE_SYMBOLS: usize = 0x800;
+
+/// Picks the code table of every symbol from the symbol before it.
+///
+/// Context `i` is the symbol selecting table `i + 1`. Table 0 is shared by
+/// the symbols following any other symbol and by the first symbol of a
+/// block.
+#[derive(Clone, Debug, Default)]
+pub struct ContextMap {
+    symbols: Vec<u32>,
+    dense: Vec<u8>,
+    sparse: HashMap<u32, u8>,
+}
+
+impl ContextMap {
+    /// Builds the map from context symbols in ascending order, at most
+    /// `MAX_CONTEXTS` of them.
+    pub fn new(symbols: Vec<u32>) -> Self {
+        debug_assert!(symbols.len() <= MAX_CONTEXTS);
+        debug_assert!(symbols.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let mut dense = Vec::new();
+        let mut sparse = HashMap::new();
+        if !symbols.is_empty() {
+            dense = vec![0; DENSE_SYMBOLS];
+        }
+        for (i, &symbol) in symbols.iter().enumerate() {
+            let table = i as u8 + 1;
+            match dense.get_mut(symbol as usize) {
+                Some(entry) => *entry = table,
+                None => {
+                    sparse.insert(symbol, table);
+                }
+            }
+        }
+
+        ContextMap {
+            symbols,
+            dense,
+            sparse,
+        }
+    }
+
+    /// The context symbols in ascending order.
+    pub fn symbols(&self) -> &[u32] {
+        &self.symbols
+    }
+
+    /// Number of contexts, not counting the shared table.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// The table of the symbol following `prev`.
+    #[inline]
+    pub fn table(&self, prev: u32) -> usize {
+        match self.dense.get(prev as usize) {
+            Some(&table) => usize::from(table),
+            None => self
+                .sparse
+                .get(&prev)
+                .map_or(0, |&table| usize::from(table)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decoder, encoder::Encoder, shared::Mode};
+
+    fn round_trip(input: &str, block_size: usize) {
+        for mode in [Mode::Text, Mode::Bytes] {
+            let mut encoder = Encoder::new(Vec::new(), mode)
+                .with_context(true)
+                .with_block_size(block_size);
+            encoder.encode(&mut input.as_bytes()).unwrap();
+
+            assert_eq!(
+                decoder::decode(encoder.into_inner()).unwrap(),
+                input.as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_tables() {
+        let map = ContextMap::new(vec![b'a'.into(), 0x7ff, 0x800, 0x1f980]);
+
+        assert_eq!(map.table(b'a'.into()), 1);
+        assert_eq!(map.table(0x7ff), 2);
+        assert_eq!(map.table(0x800), 3);
+        assert_eq!(map.table(0x1f980), 4);
+        // Other symbols share table 0 with the first symbol of a block
+        assert_eq!(map.table(b'b'.into()), 0);
+        assert_eq!(map.table(0x1f981), 0);
+        assert_eq!(ContextMap::default().table(b'a'.into()), 0);
+    }
+
+    #[test]
+    fn test_first_symbol() {
+        // Every block starts with a symbol found nowhere else in it
+        round_trip(&"zabababababababababab".repeat(50), 21);
+        round_trip("q", 1000);
+    }
+
+    #[test]
+    fn test_more_symbols_than_contexts() {
+        let input = (0..MAX_CONTEXTS as u32 * 2)
+            .flat_map(|i| {
+                let symbol = char::from_u32(0x100 + i).unwrap();
+                [symbol, 'x', symbol, 'y']
+            })
+            .collect::<String>()
+            .repeat(20);
+        round_trip(&input, 100_000);
+    }
+
+    #[test]
+    fn test_symbols_above_dense() {
+        // Contexts and coded symbols past the flat arrays
+        let input = "日本語のテキスト、🦀と🦀🦀。漢字かな交じり文 ".repeat(300);
+        round_trip(&input, 5000);
+    }
+}
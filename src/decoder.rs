@@ -1,50 +1,487 @@
 use bitvec::prelude::*;
-use std::{error::Error, io::Read, time::Instant};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Seek, Write},
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::{
+    adaptive::{self, AdaptiveTree},
+    coder::{Bwt, Coder, EntropyCoder, Huffman, Lz77, Range, Tans},
+    context::{ContextMap, MAX_CONTEXTS},
+    crc32::{Crc32, CrcWriter},
+    error::Error,
+    parallel, rle,
+    shared::{
+        canonical_codes, Mode, BLOCK_ADAPTIVE, BLOCK_END, BLOCK_NEW_TABLE, BLOCK_REUSE_TABLE,
+        FLAG_ADAPTIVE, FLAG_BLOCK_CHECKSUM, FLAG_BLOCK_INDEX, FLAG_CHECKSUM, FLAG_CONTEXT,
+        FLAG_MAX_CODE_LEN, FLAG_RLE, KNOWN_FLAGS, LENGTH_BITS, MAX_CODE_LEN, VERSION,
+    },
+    stats::Stats,
+};
+
+pub(crate) mod reader;
+mod table;
+
+use reader::BitReader;
+use table::{DecodeTable, Entry};
 
 #[derive(Clone, Debug)]
 struct Node {
-    value: Option<char>,
+    value: Option<u32>,
     left: Option<Box<Node>>,
     right: Option<Box<Node>>,
 }
 
-pub fn decode(input: Vec<u8>) -> Result<String, Box<dyn Error>> {
-    let start = Instant::now();
-    let reader = BitVec::<u8, Msb0>::from_vec(input);
-    let (tree, reader) = decode_header(&reader)?;
-    let header_duration = start.elapsed();
-    println!("Header decoding took: {:?}", header_duration);
+impl Node {
+    /// Collects the code of every leaf as (bits, length).
+    fn codes(&self) -> BTreeMap<u32, (u64, u8)> {
+        let mut codes = BTreeMap::new();
+        let mut stack = vec![(self, 0u64, 0u8)];
+
+        while let Some((node, code, len)) = stack.pop() {
+            if let Some(symbol) = node.value {
+                codes.insert(symbol, (code, len));
+            }
+            if len == 64 {
+                continue;
+            }
+            if let Some(left) = &node.left {
+                stack.push((left, code << 1, len + 1));
+            }
+            if let Some(right) = &node.right {
+                stack.push((right, (code << 1) | 1, len + 1));
+            }
+        }
+
+        codes
+    }
+}
+
+/// Fields of the file header.
+struct Header {
+    /// Position of the header in the input, block offsets in the index are
+    /// relative to it.
+    offset: u64,
+    version: u8,
+    flags: u8,
+    /// Entropy coder of the blocks, always Huffman before version 6.
+    coder: Coder,
+    /// Longest code any table may hold, when the encoder limited it.
+    max_code_len: Option<u8>,
+}
+
+impl Header {
+    /// The Huffman coder with the table options of the header.
+    fn huffman(&self) -> Huffman {
+        Huffman {
+            max_code_len: self.max_code_len,
+            context: self.flags & FLAG_CONTEXT != 0,
+            rle: self.flags & FLAG_RLE != 0,
+        }
+    }
+
+    /// The LZ77 coder with the table options of the header.
+    fn lz77(&self) -> Lz77 {
+        Lz77 {
+            max_code_len: self.max_code_len,
+        }
+    }
+
+    /// The BWT coder with the table options of the header.
+    fn bwt(&self) -> Bwt {
+        Bwt {
+            max_code_len: self.max_code_len,
+        }
+    }
+}
+
+/// Block of the index where decoding can start.
+struct Checkpoint {
+    /// Bit offset of the block from the start of the stream.
+    offset: u64,
+    /// Offset of the decoded block in the content.
+    output: u64,
+    len: u64,
+    /// Index of the block holding the code table of this one, the block
+    /// itself when it is adaptive.
+    table: usize,
+}
+
+/// Summary of a CCHF file, read without decoding its payload.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub version: u8,
+    pub mode: Mode,
+    pub coder: Coder,
+    pub checksum: bool,
+    pub block_checksums: bool,
+    pub block_index: bool,
+    pub max_code_len: Option<u8>,
+    pub adaptive: bool,
+    /// Whether tables hold one table per context of the previous symbol.
+    pub context: bool,
+    /// Whether runs of a repeated symbol are coded as run tokens.
+    pub rle: bool,
+    pub blocks: u64,
+    /// Number of blocks with their own code table.
+    pub tables: u64,
+    /// Size of the decoded content, not stored before version 5.
+    pub original_len: Option<u64>,
+    pub compressed_len: u64,
+}
+
+/// Streaming decoder reading CCHF data from `R`.
+///
+/// The payload is read through a fixed size buffer and decoded symbols are
+/// flushed to the output in chunks, so memory use does not depend on the
+/// input size.
+pub struct Decoder<R: Read> {
+    reader: BitReader<R>,
+    threads: usize,
+    /// Header and block index, read by the first `read_range`.
+    checkpoints: Option<(Header, Vec<Checkpoint>)>,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Decoder {
+            reader: BitReader::new(reader),
+            threads: 1,
+            checkpoints: None,
+        }
+    }
+
+    /// Decodes up to `threads` blocks at once. Their payloads are read ahead
+    /// into memory, which grows with the number of threads times the block
+    /// size.
+    #[cfg(feature = "parallel")]
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn decode<W: Write>(&mut self, out: &mut W) -> Result<Stats, Error> {
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        let consumed = self.reader.bytes_consumed();
+
+        let header = decode_header(&mut self.reader)?;
+        let version = header.version;
+        let mode = Mode::from_flags(header.flags);
+
+        if version < 4 {
+            // Older versions hold a single table and payload
+            let table_start = Instant::now();
+            let tree = read_tree(&mut self.reader, version, mode)?;
+            let codes = tree.codes();
+            let table = DecodeTable::new(&codes);
+            let total_bits = read_length(&mut self.reader, version)?;
+            stats.table_time = table_start.elapsed();
+            stats.distinct_symbols = codes.len() as u64;
+
+            let payload_start = Instant::now();
+            let code_set = CodeSet {
+                contexts: ContextMap::default(),
+                tables: vec![(tree, table)],
+            };
+            let mut output = Output::new(out, mode, None);
+            decode_content(&mut self.reader, &code_set, total_bits, false, &mut output)?;
+            output.finish(total_bits, &mut stats)?;
+            stats.payload_time = payload_start.elapsed();
+            stats.blocks = 1;
+        } else {
+            let reader = &mut self.reader;
+            let threads = self.threads;
+            match header.coder {
+                Coder::Huffman => {
+                    decode_blocks(&header.huffman(), reader, &header, threads, out, &mut stats)?
+                }
+                Coder::Tans => decode_blocks(&Tans, reader, &header, threads, out, &mut stats)?,
+                Coder::Range => decode_blocks(&Range, reader, &header, threads, out, &mut stats)?,
+                Coder::Lz77 => {
+                    decode_blocks(&header.lz77(), reader, &header, threads, out, &mut stats)?
+                }
+                Coder::Bwt => {
+                    decode_blocks(&header.bwt(), reader, &header, threads, out, &mut stats)?
+                }
+            }
+        }
+
+        stats.input_bytes = self.reader.bytes_consumed() - consumed;
+        stats.header_bits = stats.input_bytes * 8 - stats.payload_bits;
+        stats.total_time = start.elapsed();
 
-    let content_start = Instant::now();
-    let content = decode_content(reader, &tree)?;
-    let content_duration = content_start.elapsed();
+        Ok(stats)
+    }
+
+    /// Reads the header and block structure, skipping over every payload.
+    pub fn info(&mut self) -> Result<Info, Error> {
+        let reader = &mut self.reader;
+        let Header {
+            version,
+            flags,
+            coder,
+            max_code_len,
+            ..
+        } = decode_header(reader)?;
+        let mut info = Info {
+            version,
+            mode: Mode::from_flags(flags),
+            coder,
+            checksum: flags & FLAG_CHECKSUM != 0,
+            block_checksums: flags & FLAG_BLOCK_CHECKSUM != 0,
+            block_index: flags & FLAG_BLOCK_INDEX != 0,
+            max_code_len,
+            adaptive: flags & FLAG_ADAPTIVE != 0,
+            context: flags & FLAG_CONTEXT != 0,
+            rle: flags & FLAG_RLE != 0,
+            blocks: 0,
+            tables: 0,
+            original_len: None,
+            compressed_len: 0,
+        };
+
+        if version < 4 {
+            let table_bits = read_length(reader, version)?;
+            reader.skip_bytes(table_bits.div_ceil(8))?;
+            let total_bits = read_length(reader, version)?;
+            reader.skip_bytes(total_bits.div_ceil(8))?;
+            info.blocks = 1;
+            info.tables = 1;
+        } else {
+            loop {
+                let mut block_type = [0u8];
+                reader.read_bytes(&mut block_type)?;
+
+                match block_type[0] {
+                    BLOCK_END => break,
+                    BLOCK_NEW_TABLE if !info.adaptive => {
+                        let table_bits = read_length(reader, version)?;
+                        reader.skip_bytes(table_bits.div_ceil(8))?;
+                        info.tables += 1;
+                    }
+                    BLOCK_REUSE_TABLE if !info.adaptive && info.tables > 0 => (),
+                    BLOCK_ADAPTIVE if info.adaptive => (),
+                    _ => return Err(Error::InvalidBlock),
+                }
+
+                if version >= 5 {
+                    reader.read_varint()?;
+                }
+                let total_bits = read_length(reader, version)?;
+                reader.skip_bytes(total_bits.div_ceil(8))?;
+                if info.block_checksums {
+                    reader.skip_bytes(4)?;
+                }
+                info.blocks += 1;
+            }
+
+            if version >= 5 {
+                info.original_len = Some(reader.read_varint()?);
+            }
+            if info.checksum {
+                reader.skip_bytes(4)?;
+            }
+            if info.block_index && read_index(reader)?.len() as u64 != info.blocks {
+                return Err(Error::InvalidBlock);
+            }
+        }
+
+        info.compressed_len = reader.bytes_consumed();
+
+        Ok(info)
+    }
+}
+
+impl<R: Read + Seek> Decoder<R> {
+    /// Decodes `len` bytes of the content starting at byte `start`, the
+    /// range is cut short at the end of the content.
+    ///
+    /// The block index at the end of the stream tells where every block
+    /// starts, so only the blocks overlapping the range are decoded. The
+    /// stream has to start at the beginning of `R`, which is left at an
+    /// unspecified position. In text mode the range is in bytes of UTF-8 and
+    /// may split a character.
+    pub fn read_range(&mut self, start: u64, len: u64) -> Result<Vec<u8>, Error> {
+        let reader = &mut self.reader;
+        let (header, checkpoints) = match &mut self.checkpoints {
+            Some(checkpoints) => checkpoints,
+            checkpoints => checkpoints.insert(read_checkpoints(reader)?),
+        };
+
+        let end = start.saturating_add(len);
+        let first =
+            checkpoints.partition_point(|block| block.output.saturating_add(block.len) <= start);
+        let mut range = Vec::new();
+
+        for (i, block) in checkpoints.iter().enumerate().skip(first) {
+            if block.output >= end {
+                break;
+            }
+
+            let content = decode_block_at(reader, header, checkpoints, i)?;
+            let from = start.saturating_sub(block.output) as usize;
+            let to = (end - block.output).min(block.len) as usize;
+            range.extend_from_slice(&content[from..to]);
+        }
+
+        Ok(range)
+    }
+}
+
+/// Reads the header and the block index found at the end of the stream.
+fn read_checkpoints<R: Read + Seek>(
+    reader: &mut BitReader<R>,
+) -> Result<(Header, Vec<Checkpoint>), Error> {
+    reader.seek(0)?;
+    let header = decode_header(reader)?;
+    if header.flags & FLAG_BLOCK_INDEX == 0 {
+        return Err(Error::MissingIndex);
+    }
+
+    let stream_len = reader.stream_len()?;
+    let size_offset = stream_len.checked_sub(4).ok_or(Error::Truncated)?;
+    reader.seek(size_offset)?;
+    let mut size = [0u8; 4];
+    reader.read_bytes(&mut size)?;
+    let index_offset = size_offset
+        .checked_sub(u64::from(u32::from_le_bytes(size)))
+        .ok_or(Error::InvalidBlock)?;
+
+    reader.seek(index_offset)?;
+    let index = read_index(reader)?;
+
+    // Find the block holding the table of every block once, so decoding one
+    // does not have to look back for it
+    let mut checkpoints = Vec::with_capacity(index.len());
+    let mut output = 0u64;
+    let mut table = None;
+    let adaptive = header.flags & FLAG_ADAPTIVE != 0;
+    for (i, (offset, len)) in index.into_iter().enumerate() {
+        reader.seek(offset / 8)?;
+        let mut block_type = [0u8];
+        reader.read_bytes(&mut block_type)?;
+        table = match block_type[0] {
+            BLOCK_NEW_TABLE if !adaptive => Some(i),
+            BLOCK_REUSE_TABLE if !adaptive && table.is_some() => table,
+            BLOCK_ADAPTIVE if adaptive => Some(i),
+            _ => return Err(Error::InvalidBlock),
+        };
+
+        checkpoints.push(Checkpoint {
+            offset,
+            output,
+            len,
+            table: table.unwrap(),
+        });
+        output = output.saturating_add(len);
+    }
+
+    Ok((header, checkpoints))
+}
+
+/// Decodes block `i` of the index, reading its table from the block that
+/// carries it when it reuses an earlier one.
+fn decode_block_at<R: Read + Seek>(
+    reader: &mut BitReader<R>,
+    header: &Header,
+    checkpoints: &[Checkpoint],
+    i: usize,
+) -> Result<Vec<u8>, Error> {
+    match header.coder {
+        Coder::Huffman => decode_block_with(&header.huffman(), reader, header, checkpoints, i),
+        Coder::Tans => decode_block_with(&Tans, reader, header, checkpoints, i),
+        Coder::Range => decode_block_with(&Range, reader, header, checkpoints, i),
+        Coder::Lz77 => decode_block_with(&header.lz77(), reader, header, checkpoints, i),
+        Coder::Bwt => decode_block_with(&header.bwt(), reader, header, checkpoints, i),
+    }
+}
+
+/// Decodes block `i` of the index with the tables of `coder`.
+fn decode_block_with<C: EntropyCoder, R: Read + Seek>(
+    coder: &C,
+    reader: &mut BitReader<R>,
+    header: &Header,
+    checkpoints: &[Checkpoint],
+    i: usize,
+) -> Result<Vec<u8>, Error> {
+    let block = &checkpoints[i];
+
+    // Both offsets point at the block type, already checked
+    reader.seek(checkpoints[block.table].offset / 8 + 1)?;
+    let coding = if header.flags & FLAG_ADAPTIVE != 0 {
+        Coding::Adaptive
+    } else {
+        let table = read_block_table(coder, reader, header.version, &mut BTreeSet::new())?;
+        Coding::Table(table)
+    };
+    if block.table != i {
+        reader.seek(block.offset / 8 + 1)?;
+    }
 
-    println!("Content decoding took: {:?}", content_duration);
-    println!("Total decoding took: {:?}", start.elapsed());
+    let original_len = reader.read_varint()?;
+    let total_bits = reader.read_varint()?;
+
+    let mut content = Vec::new();
+    let mut output = Output::new(
+        &mut content,
+        Mode::from_flags(header.flags),
+        Some(original_len),
+    );
+    decode_payload(coder, reader, &coding, total_bits, &mut output)?;
+    output.finish(total_bits, &mut Stats::default())?;
+    reader.align();
+
+    if header.flags & FLAG_BLOCK_CHECKSUM != 0 {
+        let mut crc = Crc32::new();
+        crc.update(&content);
+        verify_checksum(reader, crc.finish())?;
+    }
+    if content.len() as u64 != block.len {
+        return Err(Error::InvalidBlock);
+    }
 
     Ok(content)
 }
 
-fn build_tree<'a>(iter: &mut impl Iterator<Item = &'a bool>) -> Option<Box<Node>> {
+pub fn decode(input: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut content = Vec::new();
+    Decoder::new(&input[..]).decode(&mut content)?;
+
+    Ok(content)
+}
+
+/// Deepest tree accepted from a file, deeper ones cannot come from the encoder.
+const MAX_TREE_DEPTH: usize = 64;
+
+fn build_tree<'a>(
+    iter: &mut impl Iterator<Item = &'a bool>,
+    mode: Mode,
+    depth: usize,
+) -> Option<Box<Node>> {
+    if depth > MAX_TREE_DEPTH {
+        return None;
+    }
+
     match iter.next() {
         Some(true) => {
             // Leaf node
-            let mut char_bytes = [0u8; 4];
-            for byte in char_bytes.iter_mut() {
-                *byte = iter.take(8).fold(0, |acc, b| (acc << 1) | u8::from(*b));
-            }
+            let symbol = iter
+                .take(mode.symbol_bits())
+                .fold(0, |acc, b| (acc << 1) | u32::from(*b));
 
-            let ch = char::from_u32(u32::from_be_bytes(char_bytes)).unwrap();
             Some(Box::new(Node {
-                value: Some(ch),
+                value: Some(symbol),
                 left: None,
                 right: None,
             }))
         }
         Some(false) => {
             // Internal node
-            let left = build_tree(iter);
-            let right = build_tree(iter);
+            let left = build_tree(iter, mode, depth + 1);
+            let right = build_tree(iter, mode, depth + 1);
             Some(Box::new(Node {
                 value: None,
                 left,
@@ -55,73 +492,1073 @@ fn build_tree<'a>(iter: &mut impl Iterator<Item = &'a bool>) -> Option<Box<Node>
     }
 }
 
-type DecodeResult<'a> = (Node, &'a BitSlice<u8, Msb0>);
+fn read_code_table(table: &BitSlice<u8, Msb0>) -> Result<BTreeMap<u32, u8>, Error> {
+    read_code_lengths(&mut table.iter().by_vals(), None)
+}
+
+/// Reads `count` (symbol gap, length) pairs, or pairs up to the end of
+/// `iter` without a count.
+pub(crate) fn read_code_lengths(
+    iter: &mut impl ExactSizeIterator<Item = bool>,
+    count: Option<u64>,
+) -> Result<BTreeMap<u32, u8>, Error> {
+    let mut lengths = BTreeMap::new();
+    let mut next = 0u64;
+
+    while count.map_or(iter.len() > 0, |count| (lengths.len() as u64) < count) {
+        let symbol = read_gamma(iter)
+            .and_then(|gap| next.checked_add(gap - 1)?.try_into().ok())
+            .ok_or(Error::InvalidTree)?;
+        if iter.len() < LENGTH_BITS {
+            return Err(Error::InvalidTree);
+        }
+        let len = iter
+            .by_ref()
+            .take(LENGTH_BITS)
+            .fold(0, |acc, b| (acc << 1) | u8::from(b));
+
+        lengths.insert(symbol, len);
+        next = u64::from(symbol) + 1;
+    }
+
+    // The lengths must describe a prefix code, a zero length or an over
+    // subscribed set of lengths would make codes overlap
+    let mut kraft = 0u128;
+    for &len in lengths.values() {
+        if len == 0 {
+            return Err(Error::InvalidTree);
+        }
+        kraft += 1 << (64 - len);
+    }
+    if kraft > 1 << 64 {
+        return Err(Error::InvalidTree);
+    }
+
+    Ok(lengths)
+}
+
+pub(crate) fn read_gamma(iter: &mut impl Iterator<Item = bool>) -> Option<u64> {
+    let mut zeros = 0;
+    while !iter.next()? {
+        zeros += 1;
+        if zeros >= 64 {
+            return None;
+        }
+    }
+
+    let mut n = 1u64;
+    for _ in 0..zeros {
+        n = (n << 1) | u64::from(iter.next()?);
+    }
+
+    Some(n)
+}
+
+fn tree_from_codes(codes: &BTreeMap<u32, (u64, u8)>) -> Node {
+    let mut head = Node {
+        value: None,
+        left: None,
+        right: None,
+    };
+
+    for (&symbol, &(code, len)) in codes {
+        let mut current_node = &mut head;
+        for i in (0..len).rev() {
+            let child = if (code >> i) & 1 == 1 {
+                &mut current_node.right
+            } else {
+                &mut current_node.left
+            };
+            current_node = child.get_or_insert_with(|| {
+                Box::new(Node {
+                    value: None,
+                    left: None,
+                    right: None,
+                })
+            });
+        }
+        current_node.value = Some(symbol);
+    }
+
+    head
+}
+
+fn decode_header<R: Read>(reader: &mut BitReader<R>) -> Result<Header, Error> {
+    let offset = reader.bytes_consumed();
 
-fn decode_header(mut reader: &BitSlice<u8, Msb0>) -> Result<DecodeResult, std::io::Error> {
     // Read signature
     let mut signature = [0u8; 4];
-    reader.read_exact(&mut signature)?;
+    reader.read_bytes(&mut signature)?;
     if &signature != b"CCHF" {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid file signature",
-        ));
+        return Err(Error::BadMagic);
     }
 
     // Read version
     let mut version = [0u8];
-    reader.read_exact(&mut version)?;
+    reader.read_bytes(&mut version)?;
+
+    // Version 1 files have no flags and always hold text
+    let flags = match version[0] {
+        1 => 0,
+        2..=VERSION => {
+            let mut flags = [0u8];
+            reader.read_bytes(&mut flags)?;
+            flags[0]
+        }
+        version => return Err(Error::UnsupportedVersion(version)),
+    };
+
+    // Every bit is taken for now, the check stays for when that changes
+    #[allow(clippy::bad_bit_mask)]
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(Error::UnsupportedFlags(flags));
+    }
 
+    let coder = if version[0] >= 6 {
+        let mut coder = [0u8];
+        reader.read_bytes(&mut coder)?;
+        Coder::from_id(coder[0]).ok_or(Error::UnsupportedCoder(coder[0]))?
+    } else {
+        Coder::Huffman
+    };
+    // Adaptive codes, contexts and run tokens only exist for Huffman codes,
+    // length limits for every coder with Huffman tables
+    let huffman_flags = match coder {
+        Coder::Huffman => 0,
+        Coder::Lz77 | Coder::Bwt => FLAG_ADAPTIVE | FLAG_CONTEXT | FLAG_RLE,
+        Coder::Tans | Coder::Range => FLAG_MAX_CODE_LEN | FLAG_ADAPTIVE | FLAG_CONTEXT | FLAG_RLE,
+    };
+    if flags & huffman_flags != 0 {
+        return Err(Error::UnsupportedFlags(flags));
+    }
+
+    let max_code_len = if flags & FLAG_MAX_CODE_LEN != 0 {
+        let mut max_code_len = [0u8];
+        reader.read_bytes(&mut max_code_len)?;
+        if !(1..=MAX_CODE_LEN).contains(&max_code_len[0]) {
+            return Err(Error::InvalidTree);
+        }
+        Some(max_code_len[0])
+    } else {
+        None
+    };
+
+    Ok(Header {
+        offset,
+        version: version[0],
+        flags,
+        coder,
+        max_code_len,
+    })
+}
+
+fn read_tree<R: Read>(reader: &mut BitReader<R>, version: u8, mode: Mode) -> Result<Node, Error> {
     // Read bitvec length
-    let mut bitvec_length = [0u8; 4];
-    reader.read_exact(&mut bitvec_length)?;
-    let bitvec_length = u32::from_le_bytes(bitvec_length);
+    let bitvec_length = read_length(reader, version)? as usize;
 
-    // Build the tree
-    let mut iter = reader.iter().by_refs();
-    let tree = build_tree(&mut iter).expect("Failed to build Huffman tree");
+    // Read the tree data
+    let tree_bytes = reader.read_vec(bitvec_length.div_ceil(8) as u64)?;
+    let tree_bits = BitSlice::<u8, Msb0>::from_slice(&tree_bytes);
 
-    // Advance the reader past the tree data
-    let tree_bits = (bitvec_length as usize + 7) / 8;
-    reader = &reader[tree_bits * 8..];
+    // Build the tree, versions before 3 store it directly while later ones
+    // only store the canonical code lengths
+    let tree = if version < 3 {
+        let mut iter = tree_bits.iter().by_refs();
+        *build_tree(&mut iter, mode, 0).ok_or(Error::InvalidTree)?
+    } else {
+        let lengths = read_code_table(&tree_bits[..bitvec_length])?;
+        tree_from_codes(&canonical_codes(&lengths))
+    };
 
-    Ok((*tree, reader))
+    Ok(tree)
 }
 
-fn decode_content(mut reader: &BitSlice<u8, Msb0>, head: &Node) -> Result<String, std::io::Error> {
-    let mut result = String::new();
-    let mut current_node = head;
+/// Reads the tables of a block with contexts, as written by
+/// `huffman::generate_context_table`. Returns the contexts and the tree of
+/// every table, the shared one first.
+fn read_context_tables(table_bits: &BitSlice<u8, Msb0>) -> Result<(ContextMap, Vec<Node>), Error> {
+    let mut iter = table_bits.iter().by_vals();
+
+    let count = read_gamma(&mut iter).ok_or(Error::InvalidTree)? - 1;
+    if count > MAX_CONTEXTS as u64 {
+        return Err(Error::InvalidTree);
+    }
+
+    let mut symbols = Vec::with_capacity(count as usize);
+    let mut next = 0u64;
+    for _ in 0..count {
+        let symbol = read_gamma(&mut iter)
+            .and_then(|gap| next.checked_add(gap - 1)?.try_into().ok())
+            .ok_or(Error::InvalidTree)?;
+        symbols.push(symbol);
+        next = u64::from(symbol) + 1;
+    }
 
-    let mut total_bits = [0u8; 4];
-    reader.read_exact(&mut total_bits)?;
-    let total_bits = u32::from_le_bytes(total_bits);
+    let mut trees = Vec::with_capacity(symbols.len() + 1);
+    for _ in 0..=count {
+        let entries = read_gamma(&mut iter).ok_or(Error::InvalidTree)? - 1;
+        let lengths = read_code_lengths(&mut iter, Some(entries))?;
+        trees.push(tree_from_codes(&canonical_codes(&lengths)));
+    }
+    if iter.len() > 0 {
+        return Err(Error::InvalidTree);
+    }
+
+    Ok((ContextMap::new(symbols), trees))
+}
 
-    let mut iter = reader.iter();
+/// Reads a length field, a `u32` before version 5 and a varint since.
+fn read_length<R: Read>(reader: &mut BitReader<R>, version: u8) -> Result<u64, Error> {
+    if version >= 5 {
+        reader.read_varint()
+    } else {
+        let mut length = [0u8; 4];
+        reader.read_bytes(&mut length)?;
+        Ok(u64::from(u32::from_le_bytes(length)))
+    }
+}
 
-    for _ in 0..total_bits {
-        let bit = iter.next().expect("Unexpected end of input");
+/// Reads the table stored with a block and parses it with `coder`, the
+/// symbols of the table are added to `symbols`.
+fn read_block_table<C: EntropyCoder, R: Read>(
+    coder: &C,
+    reader: &mut BitReader<R>,
+    version: u8,
+    symbols: &mut BTreeSet<u32>,
+) -> Result<Arc<C::DecodeTable>, Error> {
+    let table_len = read_length(reader, version)? as usize;
+    let table_bytes = reader.read_vec(table_len.div_ceil(8) as u64)?;
+    let table_bits = BitSlice::<u8, Msb0>::from_slice(&table_bytes);
+
+    Ok(Arc::new(
+        coder.read_table(&table_bits[..table_len], symbols)?,
+    ))
+}
+
+/// Reads the code tables of a block, one per context with `context`,
+/// checking their codes against `max_code_len`. The symbols of the tables
+/// are added to `symbols`.
+pub(crate) fn read_code_set(
+    table_bits: &BitSlice<u8, Msb0>,
+    context: bool,
+    max_code_len: Option<u8>,
+    symbols: &mut BTreeSet<u32>,
+) -> Result<CodeSet, Error> {
+    let (contexts, trees) = if context {
+        read_context_tables(table_bits)?
+    } else {
+        let lengths = read_code_table(table_bits)?;
+        let tree = tree_from_codes(&canonical_codes(&lengths));
+        (ContextMap::default(), vec![tree])
+    };
+
+    CodeSet::new(contexts, trees, max_code_len, symbols)
+}
+
+/// Code tables of a block, `contexts` picking the table of every symbol from
+/// the symbol before it. Without contexts there is a single table.
+pub(crate) struct CodeSet {
+    contexts: ContextMap,
+    tables: Vec<(Node, DecodeTable)>,
+}
+
+impl CodeSet {
+    fn new(
+        contexts: ContextMap,
+        trees: Vec<Node>,
+        max_code_len: Option<u8>,
+        symbols: &mut BTreeSet<u32>,
+    ) -> Result<Self, Error> {
+        let mut tables = Vec::with_capacity(trees.len());
+        for tree in trees {
+            let codes = tree.codes();
+            if let Some(max_code_len) = max_code_len {
+                if codes.values().any(|&(_, len)| len > max_code_len) {
+                    return Err(Error::InvalidTree);
+                }
+            }
+
+            let table = DecodeTable::new(&codes);
+            symbols.extend(codes.into_keys());
+            tables.push((tree, table));
+        }
+
+        Ok(CodeSet { contexts, tables })
+    }
+
+    /// Single table with the canonical codes of `lengths`, checked against
+    /// `max_code_len`.
+    pub(crate) fn from_lengths(
+        lengths: &BTreeMap<u32, u8>,
+        max_code_len: Option<u8>,
+    ) -> Result<Self, Error> {
+        let tree = tree_from_codes(&canonical_codes(lengths));
+        CodeSet::new(
+            ContextMap::default(),
+            vec![tree],
+            max_code_len,
+            &mut BTreeSet::new(),
+        )
+    }
+
+    /// Decodes the next symbol with the first table, taking its bits off
+    /// `remaining`.
+    #[inline]
+    pub(crate) fn read_symbol<R: Read>(
+        &self,
+        reader: &mut BitReader<R>,
+        remaining: &mut u64,
+    ) -> Result<u32, Error> {
+        reader.refill()?;
+        read_symbol(reader, &self.tables[0], remaining)
+    }
+}
+
+/// How the payload of a block is coded.
+enum Coding<T> {
+    /// Table stored with the block or an earlier one, shared by the blocks
+    /// reusing it.
+    Table(Arc<T>),
+    /// Adaptive Huffman codes from a tree starting empty.
+    Adaptive,
+}
+
+/// Block whose payload was read ahead to be decoded on another thread.
+struct PendingBlock<T> {
+    offset: u64,
+    coding: Coding<T>,
+    original_len: Option<u64>,
+    total_bits: u64,
+    payload: Vec<u8>,
+    crc: Option<u32>,
+}
+
+fn decode_blocks<C: EntropyCoder, R: Read, W: Write>(
+    coder: &C,
+    reader: &mut BitReader<R>,
+    header: &Header,
+    threads: usize,
+    out: &mut W,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    let Header { version, flags, .. } = *header;
+    let mode = Mode::from_flags(flags);
+    let adaptive = flags & FLAG_ADAPTIVE != 0;
+    let mut out = CrcWriter::new(out);
+    let mut table: Option<Arc<C::DecodeTable>> = None;
+    let mut distinct = BTreeSet::new();
+    let mut index = Vec::new();
+    let mut pending = Vec::new();
+
+    loop {
+        let offset = (reader.bytes_consumed() - header.offset) * 8;
+        let mut block_type = [0u8];
+        reader.read_bytes(&mut block_type)?;
+
+        let table_start = Instant::now();
+        match block_type[0] {
+            BLOCK_END => break,
+            BLOCK_NEW_TABLE if !adaptive => {
+                table = Some(read_block_table(coder, reader, version, &mut distinct)?)
+            }
+            BLOCK_REUSE_TABLE if !adaptive && table.is_some() => (),
+            BLOCK_ADAPTIVE if adaptive => (),
+            _ => return Err(Error::InvalidBlock),
+        }
 
-        current_node = if *bit {
-            current_node.right.as_ref().expect("Invalid Huffman tree")
+        // Versions before 5 do not store the decoded length of a block
+        let original_len = if version >= 5 {
+            Some(reader.read_varint()?)
         } else {
-            current_node.left.as_ref().expect("Invalid Huffman tree")
+            None
+        };
+        let total_bits = read_length(reader, version)?;
+        stats.table_time += table_start.elapsed();
+
+        let coding = match &table {
+            Some(table) => Coding::Table(Arc::clone(table)),
+            None => Coding::Adaptive,
         };
+        if threads > 1 {
+            // Payloads are byte aligned, so a block can be decoded on its own
+            // once its bytes are read
+            let payload = reader.read_vec(total_bits.div_ceil(8))?;
+            let crc = if flags & FLAG_BLOCK_CHECKSUM != 0 {
+                let mut crc = [0u8; 4];
+                reader.read_bytes(&mut crc)?;
+                Some(u32::from_le_bytes(crc))
+            } else {
+                None
+            };
+            pending.push(PendingBlock {
+                offset,
+                coding,
+                original_len,
+                total_bits,
+                payload,
+                crc,
+            });
+
+            if pending.len() == threads {
+                decode_pending(
+                    coder,
+                    &mut pending,
+                    mode,
+                    threads,
+                    &mut out,
+                    &mut index,
+                    &mut distinct,
+                    stats,
+                )?;
+            }
+            continue;
+        }
+
+        let payload_start = Instant::now();
+        let mut block_out = CrcWriter::new(&mut out);
+        let mut output = Output::new(&mut block_out, mode, original_len);
+        let symbols = decode_payload(coder, reader, &coding, total_bits, &mut output)?;
+        output.finish(total_bits, stats)?;
+        distinct.extend(symbols);
+        reader.align();
+        stats.payload_time += payload_start.elapsed();
+        stats.blocks += 1;
+        index.push((offset, block_out.len()));
+
+        if flags & FLAG_BLOCK_CHECKSUM != 0 {
+            verify_checksum(reader, block_out.crc())?;
+        }
+    }
+    decode_pending(
+        coder,
+        &mut pending,
+        mode,
+        threads,
+        &mut out,
+        &mut index,
+        &mut distinct,
+        stats,
+    )?;
+
+    if version >= 5 {
+        let expected = reader.read_varint()?;
+        if expected != out.len() {
+            return Err(Error::LengthMismatch {
+                expected,
+                actual: out.len(),
+            });
+        }
+    }
+
+    if flags & FLAG_CHECKSUM != 0 {
+        verify_checksum(reader, out.crc())?;
+    }
+
+    // The index only repeats what the blocks already told, it has to agree
+    if flags & FLAG_BLOCK_INDEX != 0 && read_index(reader)? != index {
+        return Err(Error::InvalidBlock);
+    }
+    stats.distinct_symbols = distinct.len() as u64;
+
+    Ok(())
+}
+
+/// Decodes the read ahead blocks on up to `threads` threads and writes them
+/// out in order.
+#[allow(clippy::too_many_arguments)]
+fn decode_pending<C: EntropyCoder, W: Write>(
+    coder: &C,
+    pending: &mut Vec<PendingBlock<C::DecodeTable>>,
+    mode: Mode,
+    threads: usize,
+    out: &mut W,
+    index: &mut Vec<(u64, u64)>,
+    distinct: &mut BTreeSet<u32>,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    let payload_start = Instant::now();
+    let results = parallel::map(pending, threads, |block| {
+        let mut reader = BitReader::new(&block.payload[..]);
+        let mut content = Vec::new();
+        let mut block_stats = Stats::default();
+        let mut output = Output::new(&mut content, mode, block.original_len);
+        let symbols = decode_payload(
+            coder,
+            &mut reader,
+            &block.coding,
+            block.total_bits,
+            &mut output,
+        )?;
+        output.finish(block.total_bits, &mut block_stats)?;
+
+        Ok::<_, Error>((content, symbols, block_stats))
+    });
+
+    for (block, result) in pending.drain(..).zip(results) {
+        let (content, symbols, block_stats) = result?;
+        distinct.extend(symbols);
+        if let Some(expected) = block.crc {
+            let mut crc = Crc32::new();
+            crc.update(&content);
+            let actual = crc.finish();
+            if expected != actual {
+                return Err(Error::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        out.write_all(&content)?;
+        index.push((block.offset, content.len() as u64));
+        stats.blocks += 1;
+        stats.symbols += block_stats.symbols;
+        stats.output_bytes += block_stats.output_bytes;
+        stats.payload_bits += block_stats.payload_bits;
+    }
+    stats.payload_time += payload_start.elapsed();
+
+    Ok(())
+}
+
+/// Reads the block index written by `writer::write_index` as (bit offset,
+/// decoded size) pairs, checking the size stored after it.
+fn read_index<R: Read>(reader: &mut BitReader<R>) -> Result<Vec<(u64, u64)>, Error> {
+    let start = reader.bytes_consumed();
+    let count = reader.read_varint()?;
+
+    let mut index = Vec::new();
+    let mut offset = 0u64;
+    for _ in 0..count {
+        offset = offset
+            .checked_add(reader.read_varint()?)
+            .ok_or(Error::InvalidBlock)?;
+        index.push((offset, reader.read_varint()?));
+    }
+
+    let mut size = [0u8; 4];
+    reader.read_bytes(&mut size)?;
+    if u64::from(u32::from_le_bytes(size)) != reader.bytes_consumed() - 4 - start {
+        return Err(Error::InvalidBlock);
+    }
 
-        if let Some(ch) = current_node.value {
-            result.push(ch);
-            current_node = head; // Reset to the root
+    Ok(index)
+}
+
+fn verify_checksum<R: Read>(reader: &mut BitReader<R>, crc: u32) -> Result<(), Error> {
+    let mut expected = [0u8; 4];
+    reader.read_bytes(&mut expected)?;
+
+    let expected = u32::from_le_bytes(expected);
+    if expected != crc {
+        return Err(Error::ChecksumMismatch {
+            expected,
+            actual: crc,
+        });
+    }
+
+    Ok(())
+}
+
+/// Decoded output is handed to the writer in chunks of this size.
+const OUTPUT_CHUNK: usize = 64 * 1024;
+
+/// Sink for the decoded symbols of a block, turning them into bytes and
+/// handing them to the writer in chunks of `OUTPUT_CHUNK` bytes.
+pub(crate) struct Output<'a, W: Write> {
+    out: &'a mut W,
+    mode: Mode,
+    buf: Vec<u8>,
+    written: u64,
+    symbols: u64,
+    /// Decoded size of the block, when the file stores it.
+    expected: Option<u64>,
+    /// Most bytes the block may decode to, `expected` when it is stored.
+    limit: u64,
+}
+
+impl<'a, W: Write> Output<'a, W> {
+    pub fn new(out: &'a mut W, mode: Mode, expected: Option<u64>) -> Self {
+        let capacity = expected.map_or(OUTPUT_CHUNK, |len| len.min(OUTPUT_CHUNK as u64) as usize);
+        Output {
+            out,
+            mode,
+            buf: Vec::with_capacity(capacity + 4),
+            written: 0,
+            symbols: 0,
+            expected,
+            limit: expected.unwrap_or(u64::MAX),
         }
     }
 
-    // TODO: fix that check (total_bytes is the compressed size)
-    // if result.bytes().len() != total_bytes as usize {
-    //     return Err(std::io::Error::new(
-    //         std::io::ErrorKind::InvalidData,
-    //         "Input length does not match output length",
-    //     ));
-    // }
+    /// Appends `symbol`, which has to be a Unicode scalar value in text
+    /// mode and a byte in bytes mode. Fails once the block grows past its
+    /// stored size, so a corrupt payload cannot decode to more than the
+    /// file announced.
+    #[inline]
+    pub fn push(&mut self, symbol: u32) -> Result<(), Error> {
+        match self.mode {
+            Mode::Text => {
+                let ch = char::from_u32(symbol).ok_or(Error::InvalidCodepoint(symbol))?;
+                let mut buf = [0u8; 4];
+                self.buf
+                    .extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            }
+            Mode::Bytes => {
+                let byte = u8::try_from(symbol).map_err(|_| Error::InvalidCodepoint(symbol))?;
+                self.buf.push(byte);
+            }
+        }
+        self.symbols += 1;
+        if self.len() > self.limit {
+            return Err(Error::InvalidBlock);
+        }
+
+        if self.buf.len() >= OUTPUT_CHUNK {
+            self.out.write_all(&self.buf)?;
+            self.written += self.buf.len() as u64;
+            self.buf.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Number of bytes decoded so far.
+    pub fn len(&self) -> u64 {
+        self.written + self.buf.len() as u64
+    }
+
+    /// Decoded size of the block, when the file stores it.
+    pub fn expected(&self) -> Option<u64> {
+        self.expected
+    }
+
+    /// Writes out the rest of the block and adds its symbols, bytes and
+    /// `total_bits` of payload to `stats`, checking the decoded size.
+    fn finish(mut self, total_bits: u64, stats: &mut Stats) -> Result<(), Error> {
+        self.out.write_all(&self.buf)?;
+        self.written += self.buf.len() as u64;
+
+        stats.symbols += self.symbols;
+        stats.output_bytes += self.written;
+        stats.payload_bits += total_bits;
+
+        if let Some(expected) = self.expected.filter(|&len| len != self.written) {
+            return Err(Error::LengthMismatch {
+                expected,
+                actual: self.written,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes a block payload with `coding`, returning the symbols an adaptive
+/// payload sent as literals.
+fn decode_payload<C: EntropyCoder, R: Read, W: Write>(
+    coder: &C,
+    reader: &mut BitReader<R>,
+    coding: &Coding<C::DecodeTable>,
+    total_bits: u64,
+    out: &mut Output<W>,
+) -> Result<Vec<u32>, Error> {
+    match coding {
+        Coding::Table(table) => {
+            coder.decode(table, reader, total_bits, out)?;
+            Ok(Vec::new())
+        }
+        Coding::Adaptive => {
+            let mut tree = AdaptiveTree::new();
+            decode_adaptive(reader, &mut tree, total_bits, out)?;
+            Ok(tree.symbols().collect())
+        }
+    }
+}
+
+/// Decodes `total_bits` of Huffman coded payload into `out`, expanding run
+/// tokens with `rle`.
+pub(crate) fn decode_content<R: Read, W: Write>(
+    reader: &mut BitReader<R>,
+    code_set: &CodeSet,
+    total_bits: u64,
+    rle: bool,
+    out: &mut Output<W>,
+) -> Result<(), Error> {
+    let mut remaining = total_bits;
+    // Every block starts with the shared table
+    let mut current = &code_set.tables[0];
+    let contextual = !code_set.contexts.is_empty();
+    let mut prev = None;
+
+    while remaining > 0 {
+        reader.refill()?;
+        let symbol = read_symbol(reader, current, &mut remaining)?;
+        // Past the end the reader yields zeros, which would decode forever
+        if reader.is_past_end() {
+            return Err(Error::Truncated);
+        }
+
+        if rle && symbol >= rle::RUN_BASE {
+            // A run repeats the last symbol, it cannot start a block
+            let repeated = prev.ok_or(Error::InvalidBlock)?;
+            for _ in 0..rle::repeats(symbol)? {
+                out.push(repeated)?;
+            }
+        } else {
+            out.push(symbol)?;
+            prev = Some(symbol);
+        }
+        if contextual {
+            current = &code_set.tables[code_set.contexts.table(symbol)];
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes the next symbol with the code table `(head, table)`, taking its
+/// bits off `remaining`. The reader must have been refilled.
+#[inline(always)]
+fn read_symbol<R: Read>(
+    reader: &mut BitReader<R>,
+    (head, table): &(Node, DecodeTable),
+    remaining: &mut u64,
+) -> Result<u32, Error> {
+    match table.lookup(reader) {
+        Entry::Symbol { symbol, len } => {
+            let len = u64::from(len);
+            if len > *remaining {
+                return Err(Error::Truncated);
+            }
+            reader.consume(len as u32);
+            *remaining -= len;
+            Ok(symbol)
+        }
+        Entry::Long => walk_tree(head, reader, remaining),
+        Entry::Invalid | Entry::Secondary { .. } => Err(Error::InvalidTree),
+    }
+}
+
+/// Decodes `total_bits` of adaptive payload into `out` with the codes of
+/// `tree`, updating it after every symbol the same way the encoder did.
+fn decode_adaptive<R: Read, W: Write>(
+    reader: &mut BitReader<R>,
+    tree: &mut AdaptiveTree,
+    total_bits: u64,
+    out: &mut Output<W>,
+) -> Result<(), Error> {
+    let mut remaining = total_bits;
+    let literal_bits = adaptive::literal_bits(out.mode);
+
+    while remaining > 0 {
+        let symbol = walk_adaptive(tree, reader, literal_bits, &mut remaining)?;
+        if reader.is_past_end() {
+            return Err(Error::Truncated);
+        }
+        out.push(symbol)?;
+        tree.update(symbol);
+    }
 
-    Ok(result)
+    Ok(())
+}
+
+/// Reference decoder walking the tree one bit at a time, used for codes too
+/// long for the lookup tables.
+fn walk_tree<R: Read>(
+    head: &Node,
+    bits: &mut BitReader<R>,
+    remaining: &mut u64,
+) -> Result<u32, Error> {
+    let mut current_node = head;
+
+    loop {
+        if *remaining == 0 {
+            return Err(Error::Truncated);
+        }
+
+        bits.refill()?;
+        let bit = bits.peek(1) == 1;
+        bits.consume(1);
+        *remaining -= 1;
+
+        current_node = if bit {
+            current_node.right.as_ref()
+        } else {
+            current_node.left.as_ref()
+        }
+        .ok_or(Error::InvalidTree)?;
+
+        if let Some(symbol) = current_node.value {
+            return Ok(symbol);
+        }
+    }
+}
+
+/// Walks `tree` from the root one bit at a time, reading the literal that
+/// follows the code of the NYT leaf.
+fn walk_adaptive<R: Read>(
+    tree: &AdaptiveTree,
+    bits: &mut BitReader<R>,
+    literal_bits: u32,
+    remaining: &mut u64,
+) -> Result<u32, Error> {
+    let mut current_node = tree.root();
+
+    loop {
+        bits.refill()?;
+
+        if current_node == tree.nyt() {
+            if u64::from(literal_bits) > *remaining {
+                return Err(Error::Truncated);
+            }
+            let symbol = bits.peek(literal_bits) as u32;
+            bits.consume(literal_bits);
+            *remaining -= u64::from(literal_bits);
+
+            // A symbol is sent only once, later ones use its leaf
+            if tree.leaf(symbol).is_some() {
+                return Err(Error::InvalidTree);
+            }
+            return Ok(symbol);
+        }
+        if let Some(symbol) = tree.symbol(current_node) {
+            return Ok(symbol);
+        }
+
+        if *remaining == 0 {
+            return Err(Error::Truncated);
+        }
+        let bit = bits.peek(1) == 1;
+        bits.consume(1);
+        *remaining -= 1;
+
+        current_node = tree.child(current_node, bit).ok_or(Error::InvalidTree)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoder::{huffman, writer::BitWriter, Encoder},
+        shared::FLAG_BYTES,
+    };
+
+    /// Symbols picked from `0..distinct`, evenly spread.
+    fn symbols(distinct: u32, len: u32) -> Vec<u32> {
+        (0..len)
+            .map(|i| i.wrapping_mul(2_654_435_761) % distinct)
+            .collect()
+    }
+
+    /// Codes `symbols` with the canonical codes of `lengths`, then decodes
+    /// them once with the lookup tables and once walking the tree.
+    fn decode_both(lengths: &BTreeMap<u32, u8>, symbols: &[u32]) -> (Vec<u32>, Vec<u32>) {
+        let codes = canonical_codes(lengths);
+        let mut writer = BitWriter::new();
+        for symbol in symbols {
+            let (code, len) = codes[symbol];
+            writer.put(code, u32::from(len));
+        }
+        let total_bits = writer.len();
+        let payload = writer.finish();
+
+        let table = (tree_from_codes(&codes), DecodeTable::new(&codes));
+        let mut reader = BitReader::new(&payload[..]);
+        let mut remaining = total_bits;
+        let mut looked_up = Vec::new();
+        while remaining > 0 {
+            reader.refill().unwrap();
+            looked_up.push(read_symbol(&mut reader, &table, &mut remaining).unwrap());
+        }
+
+        let mut reader = BitReader::new(&payload[..]);
+        let mut remaining = total_bits;
+        let mut walked = Vec::new();
+        while remaining > 0 {
+            walked.push(walk_tree(&table.0, &mut reader, &mut remaining).unwrap());
+        }
+
+        (looked_up, walked)
+    }
+
+    #[test]
+    fn test_table_decodes_like_tree() {
+        // Codes of every length from 1 to 40 bits, past both lookup levels
+        let mut lengths = (0..40)
+            .map(|symbol| (symbol, symbol as u8 + 1))
+            .collect::<BTreeMap<_, _>>();
+        lengths.insert(40, 40);
+        let input = symbols(41, 5000);
+
+        let (looked_up, walked) = decode_both(&lengths, &input);
+        assert_eq!(looked_up, walked);
+        assert_eq!(walked, input);
+    }
+
+    #[test]
+    fn test_table_decodes_like_tree_wide() {
+        // Many codes sharing the first level of the lookup table
+        let mut lengths = (0..256)
+            .map(|symbol| (symbol, 9))
+            .collect::<BTreeMap<_, _>>();
+        lengths.extend((256..0x10100).map(|symbol| (symbol, 17)));
+        let input = symbols(0x10100, 20_000);
+
+        let (looked_up, walked) = decode_both(&lengths, &input);
+        assert_eq!(looked_up, walked);
+        assert_eq!(walked, input);
+    }
+
+    /// Content of 2500 bytes in blocks of 1000, with or without an index.
+    fn indexed(block_index: bool) -> (Vec<u8>, Vec<u8>) {
+        let content = (0..2500u32)
+            .map(|i| (i * i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut encoder = Encoder::new(Vec::new(), Mode::Bytes)
+            .with_block_size(1000)
+            .with_block_index(block_index);
+        encoder.encode(&mut &content[..]).unwrap();
+
+        (content, encoder.into_inner())
+    }
+
+    fn read_range(encoded: &[u8], start: u64, len: u64) -> Result<Vec<u8>, Error> {
+        Decoder::new(std::io::Cursor::new(encoded)).read_range(start, len)
+    }
+
+    #[test]
+    fn test_read_range() {
+        let (content, encoded) = indexed(true);
+
+        // Empty, within one block, across blocks and up to the end
+        assert_eq!(read_range(&encoded, 1200, 0).unwrap(), b"");
+        assert_eq!(
+            read_range(&encoded, 1200, 300).unwrap(),
+            &content[1200..1500]
+        );
+        assert_eq!(
+            read_range(&encoded, 900, 1200).unwrap(),
+            &content[900..2100]
+        );
+        assert_eq!(read_range(&encoded, 2000, 500).unwrap(), &content[2000..]);
+        assert_eq!(read_range(&encoded, 0, 2500).unwrap(), content);
+    }
+
+    #[test]
+    fn test_read_range_past_end() {
+        let (content, encoded) = indexed(true);
+
+        assert_eq!(read_range(&encoded, 2400, 1000).unwrap(), &content[2400..]);
+        assert_eq!(read_range(&encoded, 2500, 10).unwrap(), b"");
+        assert_eq!(read_range(&encoded, u64::MAX, u64::MAX).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_read_range_reuses_index() {
+        let (content, encoded) = indexed(true);
+        let mut decoder = Decoder::new(std::io::Cursor::new(&encoded));
+
+        assert_eq!(decoder.read_range(2100, 50).unwrap(), &content[2100..2150]);
+        assert_eq!(decoder.read_range(10, 50).unwrap(), &content[10..60]);
+    }
+
+    #[test]
+    fn test_read_range_without_index() {
+        let (_, encoded) = indexed(false);
+
+        assert!(matches!(
+            read_range(&encoded, 0, 10),
+            Err(Error::MissingIndex)
+        ));
+    }
+
+    /// Text of four evenly spread symbols, each taking a 2 bit code.
+    fn encoded(mode: Mode) -> Vec<u8> {
+        let content = "abcd".repeat(1000);
+        let mut encoder = Encoder::new(Vec::new(), mode).with_checksum(true);
+        encoder.encode(&mut content.as_bytes()).unwrap();
+
+        encoder.into_inner()
+    }
+
+    #[test]
+    fn test_flipped_payload_bit() {
+        let mut encoded = encoded(Mode::Bytes);
+        let middle = encoded.len() / 2;
+        encoded[middle] ^= 0b0001_0000;
+
+        assert!(matches!(
+            decode(encoded),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let encoded = encoded(Mode::Bytes);
+        for len in [3, 6, encoded.len() / 2, encoded.len() - 1] {
+            assert!(matches!(
+                decode(encoded[..len].to_vec()),
+                Err(Error::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut encoded = encoded(Mode::Bytes);
+        encoded[0] = b'X';
+
+        assert!(matches!(decode(encoded), Err(Error::BadMagic)));
+        assert!(matches!(decode(Vec::new()), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut encoded = encoded(Mode::Bytes);
+        for version in [0, VERSION + 1, u8::MAX] {
+            encoded[4] = version;
+            assert!(matches!(
+                decode(encoded.clone()),
+                Err(Error::UnsupportedVersion(v)) if v == version
+            ));
+        }
+    }
+
+    #[test]
+    fn test_over_full_code_table() {
+        // Three codes of one bit cannot form a prefix code
+        let lengths = BTreeMap::from([(0, 1), (1, 1), (2, 1)]);
+        let mut table = BitVec::<u8, Msb0>::new();
+        huffman::push_code_table(&mut table, &lengths);
+        assert!(matches!(read_code_table(&table), Err(Error::InvalidTree)));
+
+        let lengths = BTreeMap::from([(0, 1), (1, 2), (2, 2)]);
+        let mut table = BitVec::<u8, Msb0>::new();
+        huffman::push_code_table(&mut table, &lengths);
+        assert_eq!(read_code_table(&table).unwrap(), lengths);
+    }
+
+    #[test]
+    fn test_invalid_codepoint() {
+        // Text symbols above a byte, read back as bytes
+        let content = "ĀāĂă".repeat(100);
+        let mut encoder = Encoder::new(Vec::new(), Mode::Text);
+        encoder.encode(&mut content.as_bytes()).unwrap();
+        let mut encoded = encoder.into_inner();
+        encoded[5] |= FLAG_BYTES;
+
+        assert!(matches!(
+            decode(encoded),
+            Err(Error::InvalidCodepoint(0x100..=0x103))
+        ));
+
+        let mut content = Vec::new();
+        let mut output = Output::new(&mut content, Mode::Text, None);
+        assert!(matches!(
+            output.push(0xd800),
+            Err(Error::InvalidCodepoint(0xd800))
+        ));
+    }
 }
 
 // #[cfg(test)]
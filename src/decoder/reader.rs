@@ -0,0 +1,199 @@
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use crate::Error;
+
+/// Size of the buffer refilled from the underlying reader.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Buffered MSB-first bit reader.
+///
+/// Bits are kept in a `u64` so the decoder can peek a whole table index at
+/// once. Reading past the end of the input yields zero bits, callers bound
+/// their reads with the payload bit count and check `is_past_end` afterwards.
+pub struct BitReader<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+    bits: u64,
+    count: u32,
+    /// Bits consumed so far
+    position: u64,
+    /// Bytes pulled from `inner` so far
+    fetched: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R) -> Self {
+        BitReader {
+            inner,
+            buf: vec![0; BUFFER_SIZE],
+            pos: 0,
+            end: 0,
+            bits: 0,
+            count: 0,
+            position: 0,
+            fetched: 0,
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), Error> {
+        self.end = loop {
+            match self.inner.read(&mut self.buf) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        self.pos = 0;
+        self.fetched += self.end as u64;
+
+        Ok(())
+    }
+
+    /// Tops the bit buffer up to at least 57 bits.
+    #[inline]
+    pub fn refill(&mut self) -> Result<(), Error> {
+        while self.count <= 56 {
+            if self.pos == self.end {
+                self.fill()?;
+            }
+            let byte = if self.pos < self.end {
+                self.pos += 1;
+                self.buf[self.pos - 1]
+            } else {
+                0
+            };
+            self.bits |= u64::from(byte) << (56 - self.count);
+            self.count += 8;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next `n` bits without consuming them, `n` must be in
+    /// `1..=57` and the buffer refilled beforehand.
+    #[inline]
+    pub fn peek(&self, n: u32) -> u64 {
+        self.bits >> (64 - n)
+    }
+
+    #[inline]
+    pub fn consume(&mut self, n: u32) {
+        self.bits <<= n;
+        self.count -= n;
+        self.position += u64::from(n);
+    }
+
+    /// Whether more bits were consumed than the input holds.
+    pub fn is_past_end(&self) -> bool {
+        self.position > self.fetched * 8
+    }
+
+    /// Skips to the next byte boundary.
+    pub fn align(&mut self) {
+        // The rest of a partially consumed byte is always buffered
+        self.consume(self.count % 8);
+    }
+
+    /// Reads an unsigned LEB128 varint, the reader must be byte aligned.
+    pub fn read_varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8];
+            self.read_bytes(&mut byte)?;
+            value |= u64::from(byte[0] & 0x7F) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Error::InvalidBlock)
+    }
+
+    /// Reads whole bytes, the reader must be byte aligned.
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        debug_assert_eq!(self.position % 8, 0);
+
+        for byte in out.iter_mut() {
+            if self.count == 0 {
+                if self.pos == self.end {
+                    self.fill()?;
+                }
+                if self.pos == self.end {
+                    return Err(Error::Truncated);
+                }
+                *byte = self.buf[self.pos];
+                self.pos += 1;
+                self.position += 8;
+            } else {
+                *byte = self.peek(8) as u8;
+                self.consume(8);
+            }
+        }
+
+        if self.is_past_end() {
+            return Err(Error::Truncated);
+        }
+
+        Ok(())
+    }
+
+    /// Skips `len` whole bytes.
+    pub fn skip_bytes(&mut self, len: u64) -> Result<(), Error> {
+        let mut scratch = [0u8; 4096];
+        let mut left = len;
+
+        while left > 0 {
+            let n = left.min(scratch.len() as u64) as usize;
+            self.read_bytes(&mut scratch[..n])?;
+            left -= n as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Number of whole bytes consumed so far.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.position.div_ceil(8)
+    }
+
+    /// Reads `len` whole bytes into a new vector, growing it as data arrives
+    /// so a corrupt length cannot trigger a huge allocation.
+    pub fn read_vec(&mut self, len: u64) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        let mut left = len;
+
+        while left > 0 {
+            let n = left.min(BUFFER_SIZE as u64) as usize;
+            let start = out.len();
+            out.resize(start + n, 0);
+            self.read_bytes(&mut out[start..])?;
+            left -= n as u64;
+        }
+
+        Ok(out)
+    }
+}
+
+impl<R: Read + Seek> BitReader<R> {
+    /// Moves to byte `pos` of the underlying reader, dropping buffered bits.
+    pub fn seek(&mut self, pos: u64) -> Result<(), Error> {
+        self.inner.seek(SeekFrom::Start(pos))?;
+        self.pos = 0;
+        self.end = 0;
+        self.bits = 0;
+        self.count = 0;
+        self.position = pos * 8;
+        self.fetched = pos;
+
+        Ok(())
+    }
+
+    /// Size of the underlying reader in bytes, the position is lost and has
+    /// to be restored with `seek`.
+    pub fn stream_len(&mut self) -> Result<u64, Error> {
+        Ok(self.inner.seek(SeekFrom::End(0))?)
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::BTreeMap, io::Read};
+
+use super::reader::BitReader;
+
+/// Number of bits resolved by the primary lookup table.
+const PRIMARY_BITS: u32 = 11;
+/// Largest secondary table, codes that do not fit fall back to the tree.
+const SECONDARY_BITS: u32 = 12;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Entry {
+    /// No code starts with these bits.
+    Invalid,
+    /// A complete code of `len` bits decoding to `symbol`.
+    Symbol { symbol: u32, len: u8 },
+    /// Codes longer than the primary index, resolved in `secondary`.
+    Secondary { offset: u32, bits: u8 },
+    /// Codes too long for any table, resolved by walking the tree.
+    Long,
+}
+
+/// Two level lookup table for prefix codes.
+///
+/// The primary table is indexed by the next `PRIMARY_BITS` bits. Short codes
+/// are replicated across every index they prefix, so a single lookup yields
+/// the symbol and its length. Longer codes sharing a primary prefix get their
+/// own secondary table indexed by the bits that follow.
+pub struct DecodeTable {
+    primary: Vec<Entry>,
+    secondary: Vec<Entry>,
+}
+
+impl DecodeTable {
+    pub fn new(codes: &BTreeMap<u32, (u64, u8)>) -> Self {
+        let mut primary = vec![Entry::Invalid; 1 << PRIMARY_BITS];
+        let mut secondary = Vec::new();
+
+        // Longest code under each primary prefix, for sizing secondary tables
+        let mut long_prefixes: BTreeMap<u64, u32> = BTreeMap::new();
+
+        for (&symbol, &(code, len)) in codes {
+            let len32 = u32::from(len);
+            if len == 0 {
+                continue;
+            }
+
+            if len32 <= PRIMARY_BITS {
+                let shift = PRIMARY_BITS - len32;
+                let base = (code << shift) as usize;
+                primary[base..base + (1 << shift)].fill(Entry::Symbol { symbol, len });
+            } else {
+                let prefix = code >> (len32 - PRIMARY_BITS);
+                let max_len = long_prefixes.entry(prefix).or_insert(0);
+                *max_len = (*max_len).max(len32);
+            }
+        }
+
+        for (&prefix, &max_len) in &long_prefixes {
+            let bits = max_len - PRIMARY_BITS;
+            primary[prefix as usize] = if bits <= SECONDARY_BITS {
+                let offset = secondary.len() as u32;
+                secondary.resize(secondary.len() + (1 << bits), Entry::Invalid);
+                Entry::Secondary {
+                    offset,
+                    bits: bits as u8,
+                }
+            } else {
+                Entry::Long
+            };
+        }
+
+        for (&symbol, &(code, len)) in codes {
+            let len32 = u32::from(len);
+            if len32 <= PRIMARY_BITS {
+                continue;
+            }
+
+            let prefix = code >> (len32 - PRIMARY_BITS);
+            if let Entry::Secondary { offset, bits } = primary[prefix as usize] {
+                let rest = len32 - PRIMARY_BITS;
+                let shift = u32::from(bits) - rest;
+                let low = code & ((1 << rest) - 1);
+                let base = offset as usize + (low << shift) as usize;
+                secondary[base..base + (1 << shift)].fill(Entry::Symbol { symbol, len });
+            }
+        }
+
+        DecodeTable { primary, secondary }
+    }
+
+    /// Looks up the next code, the reader must have been refilled.
+    #[inline]
+    pub fn lookup<R: Read>(&self, reader: &BitReader<R>) -> Entry {
+        match self.primary[reader.peek(PRIMARY_BITS) as usize] {
+            Entry::Secondary { offset, bits } => {
+                let mask = (1 << bits) - 1;
+                let index = reader.peek(PRIMARY_BITS + u32::from(bits)) & mask;
+                self.secondary[offset as usize + index as usize]
+            }
+            entry => entry,
+        }
+    }
+}
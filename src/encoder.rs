@@ -1,14 +1,453 @@
-use std::{error::Error, fs, time::Instant};
+use std::{
+    collections::BTreeSet,
+    io::{Read, Write},
+    sync::Arc,
+    time::Instant,
+};
 
-mod huffman;
-mod writer;
+use bitvec::prelude::*;
 
-pub fn encode(input: &str, out_path: &str) -> Result<(), Box<dyn Error>> {
-    let start = Instant::now();
-    let (freq_map, bitvec) = huffman::build(input);
-    let mut file = fs::File::create(out_path)?;
-    writer::write(&mut file, input, &freq_map, bitvec)?;
-    println!("Total encoding took: {:?}", start.elapsed());
+use crate::{
+    adaptive::AdaptiveTree,
+    coder::{Bwt, Coder, EntropyCoder, Huffman, Lz77, Range, Tans},
+    error::Error,
+    parallel,
+    shared::{
+        Mode, FLAG_ADAPTIVE, FLAG_BLOCK_CHECKSUM, FLAG_BLOCK_INDEX, FLAG_CHECKSUM, FLAG_CONTEXT,
+        FLAG_MAX_CODE_LEN, FLAG_RLE, MAX_CODE_LEN, TOKEN_BASE,
+    },
+    stats::Stats,
+};
 
-    Ok(())
+pub(crate) mod huffman;
+mod reader;
+pub(crate) mod writer;
+
+pub use reader::detect_mode;
+use reader::SymbolReader;
+use writer::CountingWriter;
+
+/// Symbols of a block waiting to be encoded.
+struct Block {
+    symbols: Vec<u32>,
+    /// Size of the block in input bytes.
+    len: u64,
+    crc: u32,
+}
+
+/// Block ready to be written.
+struct EncodedBlock {
+    /// Code table stored with the block, `None` when it reuses the table of
+    /// the previous block or is adaptive.
+    table: Option<BitVec<u8, Msb0>>,
+    payload: Vec<u8>,
+    /// Size of the payload in bits.
+    bits: u64,
+}
+
+/// Default number of input bytes per block.
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 20;
+/// Largest number of input bytes per block, so symbol counts fit in a
+/// `u32`.
+pub const MAX_BLOCK_SIZE: usize = u32::MAX as usize;
+
+/// Bits a new table costs on top of its entries for the length field
+/// before them, the varint of any table under 2^28 bits.
+const TABLE_HEADER_BITS: u64 = 32;
+
+/// Streaming encoder writing CCHF data to `W`.
+///
+/// The input is split into blocks of `block_size` bytes, each coded with
+/// its own table or the table of the previous block when that is cheaper.
+/// Only one block is held in memory at a time. Tables hold Huffman codes
+/// unless another coder is picked with `with_coder`.
+///
+/// In context mode a block holds one table per context, the symbol before
+/// each symbol picking its table. In adaptive mode blocks are instead
+/// coded in a single pass with a tree updated after every symbol, starting
+/// empty in each block.
+pub struct Encoder<W: Write> {
+    writer: CountingWriter<W>,
+    mode: Mode,
+    block_size: usize,
+    checksum: bool,
+    block_checksums: bool,
+    block_index: bool,
+    coder: Coder,
+    max_code_len: Option<u8>,
+    context: bool,
+    adaptive: bool,
+    rle: bool,
+    threads: usize,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W, mode: Mode) -> Self {
+        Encoder {
+            writer: CountingWriter::new(writer),
+            mode,
+            block_size: DEFAULT_BLOCK_SIZE,
+            checksum: true,
+            block_checksums: false,
+            block_index: true,
+            coder: Coder::Huffman,
+            max_code_len: None,
+            context: false,
+            adaptive: false,
+            rle: false,
+            threads: 1,
+        }
+    }
+
+    /// Splits the input into blocks of `block_size` bytes, between 1 and
+    /// `MAX_BLOCK_SIZE`.
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size.clamp(1, MAX_BLOCK_SIZE);
+        self
+    }
+
+    /// Whether to store a CRC-32 of the whole content, on by default.
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Whether to store a CRC-32 of every block, off by default.
+    pub fn with_block_checksums(mut self, block_checksums: bool) -> Self {
+        self.block_checksums = block_checksums;
+        self
+    }
+
+    /// Whether to end the stream with an index of the block offsets and
+    /// sizes, on by default.
+    pub fn with_block_index(mut self, block_index: bool) -> Self {
+        self.block_index = block_index;
+        self
+    }
+
+    /// Codes blocks with `coder`, Huffman by default. The context, adaptive
+    /// and run-length modes only apply to Huffman codes and the code length
+    /// limit to coders with Huffman tables, they are ignored with other
+    /// coders.
+    pub fn with_coder(mut self, coder: Coder) -> Self {
+        self.coder = coder;
+        self
+    }
+
+    /// Limits codes to `max_code_len` bits, at most 63. Encoding fails when
+    /// a block holds more distinct symbols than such codes can tell apart.
+    pub fn with_max_code_len(mut self, max_code_len: u8) -> Self {
+        self.max_code_len = Some(max_code_len.clamp(1, MAX_CODE_LEN));
+        self
+    }
+
+    /// Whether to give blocks one table per context of the previous symbol,
+    /// off by default. This improves text at the cost of larger tables and
+    /// is ignored in adaptive mode.
+    pub fn with_context(mut self, context: bool) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Whether to code blocks with adaptive Huffman codes instead of a
+    /// stored table, off by default. The code length limit does not apply
+    /// to adaptive codes.
+    pub fn with_adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Whether to code runs of a repeated symbol as the symbol followed by
+    /// run tokens, off by default. This shrinks padding and sparse data and
+    /// is ignored in adaptive mode.
+    pub fn with_rle(mut self, rle: bool) -> Self {
+        self.rle = rle;
+        self
+    }
+
+    /// Encodes up to `threads` blocks at once, memory use grows with the
+    /// number of threads times the block size.
+    #[cfg(feature = "parallel")]
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = self.mode.flags();
+        if self.checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        if self.block_checksums {
+            flags |= FLAG_BLOCK_CHECKSUM;
+        }
+        if self.block_index {
+            flags |= FLAG_BLOCK_INDEX;
+        }
+        if self.coder == Coder::Huffman && self.adaptive {
+            return flags | FLAG_ADAPTIVE;
+        }
+        if self.coder == Coder::Huffman && self.context {
+            flags |= FLAG_CONTEXT;
+        }
+        if self.coder == Coder::Huffman && self.rle {
+            flags |= FLAG_RLE;
+        }
+        if self.coder.huffman_tables() && self.max_code_len.is_some() {
+            flags |= FLAG_MAX_CODE_LEN;
+        }
+        flags
+    }
+
+    /// Encodes everything from `input` until its end.
+    ///
+    /// Blocks are read in batches of one block per thread. The tables and
+    /// payloads of a batch are built in parallel, while choosing between a
+    /// new or the previous table and writing happen in block order.
+    pub fn encode<R: Read>(&mut self, input: &mut R) -> Result<Stats, Error> {
+        match self.coder {
+            Coder::Huffman => {
+                let huffman = Huffman {
+                    max_code_len: self.max_code_len,
+                    context: self.context,
+                    rle: self.rle,
+                };
+                self.encode_with(&huffman, input)
+            }
+            Coder::Tans => self.encode_with(&Tans, input),
+            Coder::Range => self.encode_with(&Range, input),
+            Coder::Lz77 => {
+                let lz77 = Lz77 {
+                    max_code_len: self.max_code_len,
+                };
+                self.encode_with(&lz77, input)
+            }
+            Coder::Bwt => {
+                let bwt = Bwt {
+                    max_code_len: self.max_code_len,
+                };
+                self.encode_with(&bwt, input)
+            }
+        }
+    }
+
+    fn encode_with<C: EntropyCoder, R: Read>(
+        &mut self,
+        coder: &C,
+        input: &mut R,
+    ) -> Result<Stats, Error> {
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        let mut distinct = BTreeSet::<u32>::new();
+        let written = self.writer.count();
+
+        let mut chunks = SymbolReader::new(input, self.mode);
+        let mut prev_table: Option<Arc<C::Table>> = None;
+        let mut index = Vec::new();
+
+        let flags = self.flags();
+        let adaptive = flags & FLAG_ADAPTIVE != 0;
+        let max_code_len = self.max_code_len.filter(|_| flags & FLAG_MAX_CODE_LEN != 0);
+        writer::write_header(&mut self.writer, flags, self.coder, max_code_len)?;
+
+        loop {
+            let batch = self.read_batch(&mut chunks)?;
+            if batch.is_empty() {
+                break;
+            }
+
+            let encoded = if adaptive {
+                self.encode_adaptive(&batch, &mut distinct, &mut stats)
+            } else {
+                self.encode_tables(coder, &batch, &mut prev_table, &mut distinct, &mut stats)?
+            };
+
+            let write_start = Instant::now();
+            for (block, encoded) in batch.iter().zip(encoded) {
+                index.push(((self.writer.count() - written) * 8, block.len));
+                if adaptive {
+                    writer::write_adaptive_block_start(&mut self.writer)?;
+                } else {
+                    writer::write_block_start(&mut self.writer, encoded.table.as_ref())?;
+                }
+                writer::write_data_len(&mut self.writer, block.len, encoded.bits)?;
+                self.writer.write_all(&encoded.payload)?;
+
+                if self.block_checksums {
+                    writer::write_checksum(&mut self.writer, block.crc)?;
+                }
+
+                stats.blocks += 1;
+                stats.symbols += block.symbols.len() as u64;
+                stats.payload_bits += encoded.bits;
+            }
+            stats.payload_time += write_start.elapsed();
+        }
+
+        writer::write_end(&mut self.writer, chunks.len())?;
+        if self.checksum {
+            writer::write_checksum(&mut self.writer, chunks.crc())?;
+        }
+        if self.block_index {
+            writer::write_index(&mut self.writer, &index)?;
+        }
+        self.writer.flush()?;
+
+        stats.input_bytes = chunks.len();
+        stats.output_bytes = self.writer.count() - written;
+        stats.header_bits = stats.output_bytes * 8 - stats.payload_bits;
+        stats.distinct_symbols = distinct.len() as u64;
+        stats.total_time = start.elapsed();
+
+        Ok(stats)
+    }
+
+    /// Builds the tables of a batch with `coder` and encodes its payloads
+    /// with them.
+    fn encode_tables<C: EntropyCoder>(
+        &self,
+        coder: &C,
+        batch: &[Block],
+        prev_table: &mut Option<Arc<C::Table>>,
+        distinct: &mut BTreeSet<u32>,
+        stats: &mut Stats,
+    ) -> Result<Vec<EncodedBlock>, Error> {
+        // Blocks share the threads left over when the batch is short
+        let block_threads = (self.threads / batch.len()).max(1);
+
+        let table_start = Instant::now();
+        let blocks = batch.iter().collect::<Vec<_>>();
+        let tables = parallel::map(&blocks, self.threads, |block| {
+            let symbols = coder.prepare(&block.symbols);
+            let freq_map = huffman::count_frequency(&symbols, block_threads);
+            let (table, bitvec, total_bits) = coder.build(&symbols, &freq_map)?;
+            Ok::<_, Error>((symbols, freq_map, Arc::new(table), bitvec, total_bits))
+        });
+
+        // Keep the previous table if it covers the block and costs less
+        // than the new one including its serialized size
+        let mut plans = Vec::with_capacity(batch.len());
+        for table in tables {
+            let (symbols, freq_map, table, bitvec, total_bits) = table?;
+            let reuse_bits = prev_table
+                .as_ref()
+                .and_then(|prev| coder.cost(prev, &symbols, &freq_map));
+
+            let plan = match reuse_bits {
+                Some(reuse_bits)
+                    if reuse_bits <= total_bits + TABLE_HEADER_BITS + bitvec.len() as u64 =>
+                {
+                    (None, prev_table.clone().unwrap(), symbols)
+                }
+                _ => {
+                    *prev_table = Some(Arc::clone(&table));
+                    (Some(bitvec), table, symbols)
+                }
+            };
+            plans.push(plan);
+            // Tokens a coder added in place of symbols are not content
+            distinct.extend(freq_map.range(..TOKEN_BASE).map(|(&symbol, _)| symbol));
+        }
+        stats.table_time += table_start.elapsed();
+
+        let payload_start = Instant::now();
+        let payloads = parallel::map(&plans, self.threads, |(_, table, symbols)| {
+            coder.encode(table, symbols)
+        });
+        stats.payload_time += payload_start.elapsed();
+
+        Ok(plans
+            .into_iter()
+            .zip(payloads)
+            .map(|((table, _, _), (payload, bits))| EncodedBlock {
+                table,
+                payload,
+                bits,
+            })
+            .collect())
+    }
+
+    /// Encodes the payloads of a batch with adaptive codes, every block
+    /// starting from an empty tree so it can be decoded on its own.
+    fn encode_adaptive(
+        &self,
+        batch: &[Block],
+        distinct: &mut BTreeSet<u32>,
+        stats: &mut Stats,
+    ) -> Vec<EncodedBlock> {
+        let mode = self.mode;
+
+        let payload_start = Instant::now();
+        let payloads = parallel::map(batch, self.threads, |block| {
+            let mut tree = AdaptiveTree::new();
+            let (payload, bits) = writer::encode_adaptive_payload(&mut tree, mode, &block.symbols);
+            (payload, bits, tree.symbols().collect::<Vec<_>>())
+        });
+        stats.payload_time += payload_start.elapsed();
+
+        payloads
+            .into_iter()
+            .map(|(payload, bits, symbols)| {
+                distinct.extend(symbols);
+                EncodedBlock {
+                    table: None,
+                    payload,
+                    bits,
+                }
+            })
+            .collect()
+    }
+
+    /// Reads the next blocks, one per thread, an empty batch means the input
+    /// has ended.
+    fn read_batch<R: Read>(&self, chunks: &mut SymbolReader<R>) -> Result<Vec<Block>, Error> {
+        let mut batch = Vec::with_capacity(self.threads);
+
+        while batch.len() < self.threads {
+            let mut symbols = Vec::new();
+            if chunks.next_block(&mut symbols, self.block_size)? == 0 {
+                break;
+            }
+            if symbols.is_empty() {
+                continue;
+            }
+
+            batch.push(Block {
+                symbols,
+                len: chunks.block_len(),
+                crc: chunks.block_crc(),
+            });
+        }
+
+        Ok(batch)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+pub fn encode(input: &[u8], mode: Mode) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder::new(Vec::new(), mode);
+    encoder.encode(&mut &input[..])?;
+
+    Ok(encoder.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_size_limits() {
+        let block_size = |size| {
+            Encoder::new(Vec::new(), Mode::Bytes)
+                .with_block_size(size)
+                .block_size
+        };
+
+        assert_eq!(block_size(0), 1);
+        assert_eq!(block_size(1000), 1000);
+        assert_eq!(block_size(usize::MAX), MAX_BLOCK_SIZE);
+    }
 }
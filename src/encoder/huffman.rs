@@ -1,62 +1,515 @@
 use bitvec::prelude::*;
 
-use crate::shared::{generate_queue, generate_tree, HuffNode};
-use std::collections::BTreeMap;
+use crate::{
+    context::{ContextMap, MAX_CONTEXTS},
+    error::Error,
+    parallel,
+    shared::{canonical_codes, generate_queue, generate_tree, HuffNode, LENGTH_BITS, TOKEN_BASE},
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+};
 
-pub fn build(input: &str) -> (BTreeMap<char, String>, BitVec<u8, Msb0>) {
-    let freq_table = calculate_frequency(input);
-    let tree = generate_tree(generate_queue(&freq_table));
-    let p = tree.clone();
-    let (codemap, bitvec) = generate_codemap_and_bittree(p);
+/// Code of every symbol as its bits and length, stored in a flat array
+/// indexed by symbol. Symbols without a code have a length of 0. Tokens
+/// from `TOKEN_BASE` on have an array of their own, so they do not make the
+/// array of the symbols span every Unicode scalar value.
+///
+/// Compact maps only keep symbols below `DENSE_SYMBOLS` in the array and
+/// the others in a hash map, for the many context tables of a block.
+#[derive(Clone, Debug, Default)]
+pub struct CodeMap {
+    codes: Vec<(u64, u8)>,
+    tokens: Vec<(u64, u8)>,
+    sparse: HashMap<u32, (u64, u8)>,
+}
+
+impl CodeMap {
+    pub(crate) fn from_codes(codes: &BTreeMap<u32, (u64, u8)>) -> Self {
+        CodeMap::with_dense(codes, TOKEN_BASE)
+    }
+
+    /// Compact map of `codes`, whose size does not depend on how large the
+    /// symbols are.
+    pub(crate) fn compact(codes: &BTreeMap<u32, (u64, u8)>) -> Self {
+        CodeMap::with_dense(codes, DENSE_SYMBOLS as u32)
+    }
 
-    (codemap, bitvec)
+    /// Map of `codes` with the symbols below `dense` in the array.
+    fn with_dense(codes: &BTreeMap<u32, (u64, u8)>, dense: u32) -> Self {
+        let mut code_map = CodeMap::default();
+        for (&symbol, &code) in codes {
+            let (flat, index) = if symbol < dense {
+                (&mut code_map.codes, symbol as usize)
+            } else if symbol >= TOKEN_BASE {
+                (&mut code_map.tokens, (symbol - TOKEN_BASE) as usize)
+            } else {
+                code_map.sparse.insert(symbol, code);
+                continue;
+            };
+            if flat.len() <= index {
+                flat.resize(index + 1, (0, 0));
+            }
+            flat[index] = code;
+        }
+
+        code_map
+    }
+
+    /// The code of `symbol` as (bits, length), a length of 0 when it has
+    /// none.
+    #[inline]
+    pub fn get(&self, symbol: u32) -> (u64, u8) {
+        let code = if symbol < TOKEN_BASE {
+            self.codes.get(symbol as usize)
+        } else {
+            self.tokens.get((symbol - TOKEN_BASE) as usize)
+        };
+        match code {
+            Some(&code) => code,
+            None if !self.sparse.is_empty() => self.sparse.get(&symbol).copied().unwrap_or((0, 0)),
+            None => (0, 0),
+        }
+    }
+
+    pub fn contains(&self, symbol: u32) -> bool {
+        self.get(symbol).1 > 0
+    }
 }
 
-fn calculate_frequency(input: &str) -> BTreeMap<char, u32> {
-    let mut map = BTreeMap::new();
+/// Code tables of a block, `contexts` picking the table of every symbol from
+/// the symbol before it. Without contexts the block has a single table.
+#[derive(Clone, Debug)]
+pub struct CodeSet {
+    pub contexts: ContextMap,
+    pub maps: Vec<CodeMap>,
+}
+
+/// Builds the codes and serialized code table for `freq_table`, with no code
+/// longer than `max_len` bits when a limit is given.
+pub fn build(
+    freq_table: &BTreeMap<u32, u32>,
+    max_len: Option<u8>,
+) -> Result<(CodeSet, BitVec<u8, Msb0>), Error> {
+    let lengths = code_lengths(freq_table, max_len)?;
+
+    let code_set = CodeSet {
+        contexts: ContextMap::default(),
+        maps: vec![CodeMap::from_codes(&canonical_codes(&lengths))],
+    };
+    let mut bitvec = BitVec::new();
+    push_code_table(&mut bitvec, &lengths);
+
+    Ok((code_set, bitvec))
+}
+
+/// Builds one code table per context for `symbols`, whose frequencies are
+/// `freq_table`. Returns the codes, their serialized tables and the size of
+/// the coded symbols in bits.
+///
+/// The most frequent symbols are tried as contexts. A context keeps a table
+/// of its own when that table and the codes it gives cost fewer bits than
+/// coding the same symbols with a table of the whole block, the symbols
+/// following any other context share table 0.
+pub fn build_contexts(
+    symbols: &[u32],
+    freq_table: &BTreeMap<u32, u32>,
+    max_len: Option<u8>,
+) -> Result<(CodeSet, BitVec<u8, Msb0>, u64), Error> {
+    let block_lengths = code_lengths(freq_table, max_len)?;
+
+    let mut candidates = freq_table
+        .iter()
+        .map(|(&symbol, &freq)| (Reverse(freq), symbol))
+        .collect::<Vec<_>>();
+    candidates.sort_unstable();
+    let mut candidates = candidates
+        .into_iter()
+        .take(MAX_CONTEXTS)
+        .map(|(_, symbol)| symbol)
+        .collect::<Vec<_>>();
+    candidates.sort_unstable();
+    let candidates = ContextMap::new(candidates);
 
-    for char in input.chars() {
-        map.entry(char).and_modify(|freq| *freq += 1).or_insert(1);
+    // Split the symbols by the candidate before them
+    let mut following = vec![Vec::new(); candidates.len() + 1];
+    let mut table = 0;
+    for &symbol in symbols {
+        following[table].push(symbol);
+        table = candidates.table(symbol);
     }
 
-    map
+    let mut shared = BTreeMap::new();
+    calculate_frequency(&mut shared, &following[0]);
+    let mut contexts = Vec::new();
+    let mut tables = vec![BTreeMap::new()];
+    let mut total_bits = 0;
+
+    for (&context, symbols) in candidates.symbols().iter().zip(&following[1..]) {
+        let mut freq_table = BTreeMap::new();
+        calculate_frequency(&mut freq_table, symbols);
+
+        let lengths = code_lengths(&freq_table, max_len)?;
+        let own_bits = coded_bits(&freq_table, &lengths);
+        let table_bits = code_table_bits(&lengths);
+        if own_bits + table_bits < coded_bits(&freq_table, &block_lengths) {
+            contexts.push(context);
+            tables.push(lengths);
+            total_bits += own_bits;
+        } else {
+            for (symbol, freq) in freq_table {
+                *shared.entry(symbol).or_insert(0) += freq;
+            }
+        }
+    }
+
+    tables[0] = code_lengths(&shared, max_len)?;
+    total_bits += coded_bits(&shared, &tables[0]);
+
+    let contexts = ContextMap::new(contexts);
+    let bitvec = generate_context_table(&contexts, &tables);
+    let code_set = CodeSet {
+        contexts,
+        // Only the shared table gets a flat array spanning its symbols, a
+        // block can have hundreds of the others
+        maps: tables
+            .iter()
+            .enumerate()
+            .map(|(i, lengths)| match i {
+                0 => CodeMap::from_codes(&canonical_codes(lengths)),
+                _ => CodeMap::compact(&canonical_codes(lengths)),
+            })
+            .collect(),
+    };
+
+    Ok((code_set, bitvec, total_bits))
 }
 
-fn generate_codemap_and_bittree(head: HuffNode) -> (BTreeMap<char, String>, BitVec<u8, Msb0>) {
-    let mut map: BTreeMap<char, String> = BTreeMap::new();
-    let mut bitvec = BitVec::<u8, Msb0>::new();
+/// Optimal code lengths for `freq_table`, limited to `max_len` bits when a
+/// limit is given.
+pub(crate) fn code_lengths(
+    freq_table: &BTreeMap<u32, u32>,
+    max_len: Option<u8>,
+) -> Result<BTreeMap<u32, u8>, Error> {
+    if freq_table.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let tree = generate_tree(generate_queue(freq_table));
+    let mut lengths = generate_code_lengths(tree);
+    if let Some(max_len) = max_len {
+        if lengths.values().any(|&len| len > max_len) {
+            lengths = limited_code_lengths(freq_table, max_len)?;
+        }
+    }
+
+    Ok(lengths)
+}
+
+/// Size in bits of the symbols of `freq_table` coded with `lengths`.
+fn coded_bits(freq_table: &BTreeMap<u32, u32>, lengths: &BTreeMap<u32, u8>) -> u64 {
+    freq_table
+        .iter()
+        .map(|(symbol, &freq)| u64::from(freq) * u64::from(lengths[symbol]))
+        .sum()
+}
+
+/// Symbols below this are counted in a flat array before being added to the
+/// map, and kept in the array of compact code maps. This covers bytes and
+/// the most common characters.
+const DENSE_SYMBOLS: usize = 0x800;
+
+pub fn calculate_frequency(map: &mut BTreeMap<u32, u32>, symbols: &[u32]) {
+    let mut dense = vec![0u32; DENSE_SYMBOLS];
+
+    for &symbol in symbols {
+        match dense.get_mut(symbol as usize) {
+            Some(freq) => *freq += 1,
+            None => *map.entry(symbol).or_insert(0) += 1,
+        }
+    }
+
+    for (symbol, freq) in dense.into_iter().enumerate() {
+        if freq > 0 {
+            *map.entry(symbol as u32).or_insert(0) += freq;
+        }
+    }
+}
+
+/// Counts the frequencies of `symbols` in chunks spread over `threads`,
+/// merging the tables of every chunk.
+pub fn count_frequency(symbols: &[u32], threads: usize) -> BTreeMap<u32, u32> {
+    let chunks = symbols
+        .chunks(symbols.len().div_ceil(threads.max(1)).max(1))
+        .collect::<Vec<_>>();
+    let maps = parallel::map(&chunks, threads, |chunk| {
+        let mut map = BTreeMap::new();
+        calculate_frequency(&mut map, chunk);
+        map
+    });
 
-    dfs(Some(Box::new(head)), "", &mut map, &mut bitvec);
+    let mut maps = maps.into_iter();
+    let mut freq_map = maps.next().unwrap_or_default();
+    for map in maps {
+        for (symbol, freq) in map {
+            *freq_map.entry(symbol).or_insert(0) += freq;
+        }
+    }
+
+    freq_map
+}
 
-    (map, bitvec)
+fn generate_code_lengths(head: HuffNode) -> BTreeMap<u32, u8> {
+    let mut map: BTreeMap<u32, u8> = BTreeMap::new();
+
+    dfs(Some(Box::new(head)), 0, &mut map);
+
+    map
 }
 
-fn dfs(
-    p: Option<Box<HuffNode>>,
-    s: &str,
-    map: &mut BTreeMap<char, String>,
-    vec: &mut BitVec<u8, Msb0>,
-) {
+fn dfs(p: Option<Box<HuffNode>>, depth: u8, map: &mut BTreeMap<u32, u8>) {
     match p {
         None => (),
         Some(p) => {
             if let Some(v) = p.value {
-                map.insert(v, s.to_string());
-                vec.push(true);
-                let char_code = v as u32;
-
-                // Push each bit of the char_code into the BitVec
-                for i in (0..32).rev() {
-                    vec.push(((char_code >> i) & 1) == 1);
-                }
+                // A lone symbol still needs a one bit code
+                map.insert(v, depth.max(1));
+            }
+
+            dfs(p.left, depth + 1, map);
+            dfs(p.right, depth + 1, map);
+        }
+    }
+}
+
+/// Optimal code lengths of at most `max_len` bits, found with the
+/// package-merge algorithm.
+///
+/// Each level holds the symbols as coins of their frequency merged with the
+/// pairs packaged from the level below. The 2n - 2 cheapest items of the top
+/// level make up the solution, and every symbol gets one bit per level in
+/// which it is part of the chosen items.
+pub(crate) fn limited_code_lengths(
+    freq_table: &BTreeMap<u32, u32>,
+    max_len: u8,
+) -> Result<BTreeMap<u32, u8>, Error> {
+    let mut leaves = freq_table
+        .iter()
+        .map(|(&symbol, &freq)| (u64::from(freq), symbol))
+        .collect::<Vec<_>>();
+    leaves.sort_unstable();
+
+    let n = leaves.len();
+    if n == 1 {
+        return Ok(BTreeMap::from([(leaves[0].1, 1)]));
+    }
+    if max_len == 0 || n as u64 > 1u64.checked_shl(u32::from(max_len)).unwrap_or(u64::MAX) {
+        return Err(Error::CodeLengthLimit(max_len));
+    }
+
+    // `levels[i]` tells for every item of the level whether it is a symbol
+    // or a package, starting from the deepest level holding only symbols
+    let limit = 2 * n - 2;
+    let mut weights = leaves.iter().map(|&(freq, _)| freq).collect::<Vec<_>>();
+    let mut levels = vec![vec![true; n]];
+
+    for _ in 1..max_len {
+        let packages = weights
+            .chunks_exact(2)
+            .map(|pair| pair[0] + pair[1])
+            .collect::<Vec<_>>();
+
+        let mut merged = Vec::with_capacity(limit);
+        let mut is_leaf = Vec::with_capacity(limit);
+        let (mut i, mut j) = (0, 0);
+        while merged.len() < limit && (i < n || j < packages.len()) {
+            // Ties go to symbols so lengths do not depend on package order
+            if j == packages.len() || (i < n && leaves[i].0 <= packages[j]) {
+                merged.push(leaves[i].0);
+                is_leaf.push(true);
+                i += 1;
             } else {
-                vec.push(false);
+                merged.push(packages[j]);
+                is_leaf.push(false);
+                j += 1;
             }
+        }
+
+        weights = merged;
+        levels.push(is_leaf);
+    }
+
+    // Walk down from the top level, the chosen packages of a level select
+    // twice as many items of the level below
+    let mut lengths = vec![0u8; n];
+    let mut chosen = limit;
+    for is_leaf in levels.iter().rev() {
+        let chosen_leaves = is_leaf[..chosen].iter().filter(|&&leaf| leaf).count();
+        for len in &mut lengths[..chosen_leaves] {
+            *len += 1;
+        }
+        chosen = 2 * (chosen - chosen_leaves);
+    }
+
+    Ok(leaves
+        .iter()
+        .zip(lengths)
+        .map(|(&(_, symbol), len)| (symbol, len))
+        .collect())
+}
+
+/// Serializes the code lengths as (symbol gap, length) pairs in ascending
+/// symbol order. Gaps are Elias gamma coded, so dense alphabets cost a few
+/// bits per symbol.
+pub(crate) fn push_code_table(bitvec: &mut BitVec<u8, Msb0>, lengths: &BTreeMap<u32, u8>) {
+    let mut next = 0u64;
+
+    for (&symbol, &len) in lengths {
+        push_gamma(bitvec, u64::from(symbol) - next + 1);
+        for i in (0..LENGTH_BITS).rev() {
+            bitvec.push(((len >> i) & 1) == 1);
+        }
+        next = u64::from(symbol) + 1;
+    }
+}
+
+/// Size in bits of the code table `push_code_table` writes for `lengths`.
+fn code_table_bits(lengths: &BTreeMap<u32, u8>) -> u64 {
+    let mut next = 0u64;
+
+    lengths
+        .keys()
+        .map(|&symbol| {
+            let bits = gamma_bits(u64::from(symbol) - next + 1);
+            next = u64::from(symbol) + 1;
+            bits + LENGTH_BITS as u64
+        })
+        .sum()
+}
 
-            dfs(p.left, &format!("{}0", s), map, vec);
-            dfs(p.right, &format!("{}1", s), map, vec);
+/// Serializes the tables of a block with contexts: the number of contexts
+/// plus one, the context symbols as gamma coded gaps, then every table as
+/// its number of entries plus one followed by its entries, the shared table
+/// first.
+fn generate_context_table(contexts: &ContextMap, tables: &[BTreeMap<u32, u8>]) -> BitVec<u8, Msb0> {
+    let mut bitvec = BitVec::<u8, Msb0>::new();
+
+    push_gamma(&mut bitvec, contexts.len() as u64 + 1);
+    let mut next = 0u64;
+    for &symbol in contexts.symbols() {
+        push_gamma(&mut bitvec, u64::from(symbol) - next + 1);
+        next = u64::from(symbol) + 1;
+    }
+
+    for lengths in tables {
+        push_gamma(&mut bitvec, lengths.len() as u64 + 1);
+        push_code_table(&mut bitvec, lengths);
+    }
+
+    bitvec
+}
+
+fn gamma_bits(n: u64) -> u64 {
+    2 * u64::from(63 - n.leading_zeros()) + 1
+}
+
+pub(crate) fn push_gamma(vec: &mut BitVec<u8, Msb0>, n: u64) {
+    let bits = 64 - n.leading_zeros() as usize;
+
+    for _ in 1..bits {
+        vec.push(false);
+    }
+    for i in (0..bits).rev() {
+        vec.push(((n >> i) & 1) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frequencies of the Fibonacci numbers, the most skewed distribution
+    /// for their count, giving the deepest Huffman tree.
+    fn fibonacci(n: u32) -> BTreeMap<u32, u32> {
+        let mut freqs = vec![1, 1];
+        while freqs.len() < n as usize {
+            freqs.push(freqs[freqs.len() - 1] + freqs[freqs.len() - 2]);
+        }
+        (0..n).zip(freqs).collect()
+    }
+
+    /// Checks that every symbol of `freq_table` has a length of at most
+    /// `max_len` and that the lengths make a prefix code.
+    fn assert_valid(freq_table: &BTreeMap<u32, u32>, lengths: &BTreeMap<u32, u8>, max_len: u8) {
+        assert!(freq_table.keys().eq(lengths.keys()));
+        assert!(lengths.values().all(|&len| (1..=max_len).contains(&len)));
+        let kraft = lengths
+            .values()
+            .map(|&len| 1u64 << (max_len - len))
+            .sum::<u64>();
+        assert!(kraft <= 1 << max_len);
+    }
+
+    #[test]
+    fn test_limit_forces_skewed_codes() {
+        let freq_table = fibonacci(30);
+        let unlimited = code_lengths(&freq_table, None).unwrap();
+        assert_eq!(unlimited.values().max(), Some(&29));
+
+        for max_len in [5, 8, 12, 20] {
+            let lengths = limited_code_lengths(&freq_table, max_len).unwrap();
+            assert_valid(&freq_table, &lengths, max_len);
+            assert_eq!(lengths.values().max(), Some(&max_len));
+            assert_eq!(code_lengths(&freq_table, Some(max_len)).unwrap(), lengths);
         }
     }
+
+    #[test]
+    fn test_limit_not_binding() {
+        // Frequencies of distinct powers of two have a single optimal code
+        let freq_table = (0..10).map(|i| (i, 1 << i)).collect::<BTreeMap<_, _>>();
+        let unlimited = code_lengths(&freq_table, None).unwrap();
+        assert_eq!(limited_code_lengths(&freq_table, 9).unwrap(), unlimited);
+        assert_eq!(limited_code_lengths(&freq_table, 30).unwrap(), unlimited);
+
+        // Otherwise the lengths may differ but not the coded size
+        let freq_table = (0..300)
+            .map(|i| (i * 7, (i * i) % 97 + 1))
+            .collect::<BTreeMap<_, _>>();
+        let unlimited = code_lengths(&freq_table, None).unwrap();
+        let lengths = limited_code_lengths(&freq_table, 24).unwrap();
+        assert_valid(&freq_table, &lengths, 24);
+        assert_eq!(
+            coded_bits(&freq_table, &lengths),
+            coded_bits(&freq_table, &unlimited)
+        );
+    }
+
+    #[test]
+    fn test_limit_too_small() {
+        let freq_table = (0..5).map(|i| (i, i + 1)).collect::<BTreeMap<_, _>>();
+        assert!(matches!(
+            limited_code_lengths(&freq_table, 2),
+            Err(Error::CodeLengthLimit(2))
+        ));
+        assert!(matches!(
+            limited_code_lengths(&freq_table, 0),
+            Err(Error::CodeLengthLimit(0))
+        ));
+        assert_valid(
+            &freq_table,
+            &limited_code_lengths(&freq_table, 3).unwrap(),
+            3,
+        );
+
+        // A lone symbol takes one bit whatever the limit
+        let lone = BTreeMap::from([(7, 3)]);
+        assert_eq!(
+            limited_code_lengths(&lone, 1).unwrap(),
+            BTreeMap::from([(7, 1)])
+        );
+    }
 }
 
 // #[cfg(test)]
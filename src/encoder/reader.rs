@@ -0,0 +1,149 @@
+use std::io::{ErrorKind, Read};
+
+use crate::{crc32::Crc32, error::Error, shared::Mode};
+
+/// Size of each chunk read from the input.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Reads the input in bounded chunks and hands out the symbols of each one.
+///
+/// In text mode a UTF-8 sequence split across two chunks is carried over to
+/// the next read, so chunk boundaries never affect the decoded symbols.
+pub struct SymbolReader<R: Read> {
+    inner: R,
+    mode: Mode,
+    buf: Vec<u8>,
+    pending: usize,
+    crc: Crc32,
+    block_crc: Crc32,
+    len: u64,
+    block_len: u64,
+}
+
+impl<R: Read> SymbolReader<R> {
+    pub fn new(inner: R, mode: Mode) -> Self {
+        SymbolReader {
+            inner,
+            mode,
+            // Room for a carried over partial UTF-8 sequence
+            buf: vec![0; CHUNK_SIZE + 4],
+            pending: 0,
+            crc: Crc32::new(),
+            block_crc: Crc32::new(),
+            len: 0,
+            block_len: 0,
+        }
+    }
+
+    /// Reads up to `limit` bytes and appends their symbols, returns the
+    /// number of bytes read or 0 once the input is exhausted.
+    pub fn next_chunk(&mut self, symbols: &mut Vec<u32>, limit: usize) -> Result<usize, Error> {
+        let limit = limit.min(CHUNK_SIZE);
+        let read = loop {
+            match self
+                .inner
+                .read(&mut self.buf[self.pending..self.pending + limit])
+            {
+                Ok(n) => break n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let filled = self.pending + read;
+        if filled == 0 {
+            return Ok(0);
+        }
+
+        match self.mode {
+            Mode::Bytes => {
+                symbols.extend(self.buf[..filled].iter().map(|&b| u32::from(b)));
+                self.track(filled);
+                self.pending = 0;
+            }
+            Mode::Text => {
+                let valid = match std::str::from_utf8(&self.buf[..filled]) {
+                    Ok(text) => text,
+                    // An incomplete sequence at the end is completed by the next read
+                    Err(e) if e.error_len().is_none() && read > 0 => {
+                        std::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap()
+                    }
+                    Err(_) => return Err(Error::InvalidUtf8),
+                };
+                symbols.extend(valid.chars().map(u32::from));
+                let used = valid.len();
+                self.track(used);
+                self.buf.copy_within(used..filled, 0);
+                self.pending = filled - used;
+            }
+        }
+
+        Ok(read)
+    }
+
+    /// Replaces `symbols` with the next `block_size` bytes of input, returns
+    /// the number of bytes read or 0 once the input is exhausted.
+    pub fn next_block(
+        &mut self,
+        symbols: &mut Vec<u32>,
+        block_size: usize,
+    ) -> Result<usize, Error> {
+        symbols.clear();
+        self.block_crc = Crc32::new();
+        self.block_len = 0;
+
+        let mut total = 0;
+        while total < block_size {
+            match self.next_chunk(symbols, block_size - total)? {
+                0 => break,
+                n => total += n,
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Accounts for the first `used` bytes of the buffer having been turned
+    /// into symbols.
+    fn track(&mut self, used: usize) {
+        self.crc.update(&self.buf[..used]);
+        self.block_crc.update(&self.buf[..used]);
+        self.len += used as u64;
+        self.block_len += used as u64;
+    }
+
+    /// Size of the content of every symbol read so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Size of the content of the symbols of the current block.
+    pub fn block_len(&self) -> u64 {
+        self.block_len
+    }
+
+    /// CRC-32 of the content of every symbol read so far.
+    pub fn crc(&self) -> u32 {
+        self.crc.finish()
+    }
+
+    /// CRC-32 of the content of the symbols of the current block.
+    pub fn block_crc(&self) -> u32 {
+        self.block_crc.finish()
+    }
+}
+
+/// Scans the whole input and picks text mode if it is valid UTF-8.
+pub fn detect_mode<R: Read>(input: R) -> Result<Mode, Error> {
+    let mut reader = SymbolReader::new(input, Mode::Text);
+    let mut symbols = Vec::new();
+
+    loop {
+        symbols.clear();
+        match reader.next_chunk(&mut symbols, CHUNK_SIZE) {
+            Ok(0) => return Ok(Mode::Text),
+            Ok(_) => (),
+            Err(Error::InvalidUtf8) => return Ok(Mode::Bytes),
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -1,53 +1,278 @@
 use bitvec::prelude::*;
-use std::{collections::BTreeMap, error::Error, io::Write, time::Instant};
+use std::io::{self, Write};
 
-pub fn write<W: Write>(
+use super::huffman::CodeSet;
+use crate::{
+    adaptive::{self, AdaptiveTree},
+    coder::Coder,
+    error::Error,
+    shared::{Mode, BLOCK_ADAPTIVE, BLOCK_END, BLOCK_NEW_TABLE, BLOCK_REUSE_TABLE, VERSION},
+};
+
+/// Writer adapter counting the bytes written through it.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn write_header<W: Write>(
     writer: &mut W,
-    input: &str,
-    code_map: &BTreeMap<char, String>,
-    bitvec: BitVec<u8, Msb0>,
-) -> Result<(), Box<dyn Error>> {
-    write_header(writer, bitvec)?;
-    write_data(writer, code_map, input)?;
+    flags: u8,
+    coder: Coder,
+    max_code_len: Option<u8>,
+) -> Result<(), Error> {
+    writer.write_all(b"CCHF")?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&[flags])?;
+    writer.write_all(&[coder.id()])?;
+    if let Some(max_code_len) = max_code_len {
+        writer.write_all(&[max_code_len])?;
+    }
 
     Ok(())
 }
 
-fn write_header<W: Write>(writer: &mut W, bitvec: BitVec<u8, Msb0>) -> Result<(), Box<dyn Error>> {
-    writer.write_all(b"CCHF")?;
-    writer.write_all(&[1])?;
+/// Starts a block, `bitvec` holds its code table or `None` to reuse the
+/// table of the previous block.
+pub fn write_block_start<W: Write>(
+    writer: &mut W,
+    bitvec: Option<&BitVec<u8, Msb0>>,
+) -> Result<(), Error> {
+    match bitvec {
+        Some(bitvec) => {
+            writer.write_all(&[BLOCK_NEW_TABLE])?;
+            write_varint(writer, bitvec.len() as u64)?;
+            writer.write_all(bitvec.as_raw_slice())?;
+        }
+        None => writer.write_all(&[BLOCK_REUSE_TABLE])?,
+    }
+
+    Ok(())
+}
 
-    // TODO: Add original input length later
-    writer.write_all(&(bitvec.len() as u32).to_le_bytes())?;
-    writer.write_all(bitvec.as_raw_slice())?;
+/// Starts a block coded with an adaptive tree, which carries no table.
+pub fn write_adaptive_block_start<W: Write>(writer: &mut W) -> Result<(), Error> {
+    writer.write_all(&[BLOCK_ADAPTIVE])?;
 
     Ok(())
 }
 
-fn write_data<W: Write>(
-    writer: &mut W,
-    code_map: &BTreeMap<char, String>,
-    input: &str,
-) -> Result<(), Box<dyn Error>> {
-    let mut bitvec = BitVec::<u8, Msb0>::new();
+/// Ends the block list, followed by the decoded size of the whole content.
+pub fn write_end<W: Write>(writer: &mut W, original_len: u64) -> Result<(), Error> {
+    writer.write_all(&[BLOCK_END])?;
+    write_varint(writer, original_len)?;
+
+    Ok(())
+}
 
-    let inst = Instant::now();
-    for char in input.chars() {
-        let code = code_map.get(&char).unwrap();
+/// Writes the block index, the number of blocks followed by the bit offset
+/// of every block from the start of the stream and its decoded size. Offsets
+/// are stored as the distance from the previous block. The size of the index
+/// in bytes comes last as a `u32`, so it can be found from the end of the
+/// stream.
+pub fn write_index<W: Write>(writer: &mut W, blocks: &[(u64, u64)]) -> Result<(), Error> {
+    let mut index = Vec::new();
+    write_varint(&mut index, blocks.len() as u64)?;
 
-        for code_bit in code.chars() {
-            bitvec.push(code_bit == '1');
+    let mut prev_offset = 0;
+    for &(offset, original_len) in blocks {
+        write_varint(&mut index, offset - prev_offset)?;
+        write_varint(&mut index, original_len)?;
+        prev_offset = offset;
+    }
+
+    writer.write_all(&index)?;
+    writer.write_all(&(index.len() as u32).to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Writes an unsigned LEB128 varint.
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> Result<(), Error> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
         }
+        buf[len] = byte | 0x80;
+        len += 1;
     }
+    writer.write_all(&buf[..len])?;
+
+    Ok(())
+}
+
+pub fn write_checksum<W: Write>(writer: &mut W, crc: u32) -> Result<(), Error> {
+    writer.write_all(&crc.to_le_bytes())?;
+
+    Ok(())
+}
 
-    dbg!(inst.elapsed());
-    // Calculate and write total byte size
-    writer.write_all(&(bitvec.len() as u32).to_le_bytes())?;
-    writer.write_all(bitvec.as_raw_slice())?;
+/// Writes the decoded size of a block in bytes followed by its payload size
+/// in bits.
+pub fn write_data_len<W: Write>(
+    writer: &mut W,
+    original_len: u64,
+    total_bits: u64,
+) -> Result<(), Error> {
+    write_varint(writer, original_len)?;
+    write_varint(writer, total_bits)?;
 
     Ok(())
 }
 
+/// Bit writer packing codes into a 64-bit accumulator, which is moved to a
+/// byte buffer one whole word at a time.
+pub struct BitWriter {
+    buf: Vec<u8>,
+    acc: u64,
+    /// Number of pending bits in the low end of `acc`, always below 64.
+    bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter {
+            buf: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Appends the low `len` bits of `code`, most significant bit first.
+    #[inline]
+    pub fn put(&mut self, code: u64, len: u32) {
+        let free = 64 - self.bits;
+        if len < free {
+            self.acc = (self.acc << len) | code;
+            self.bits += len;
+        } else {
+            // `len` is below 64, so `free` is too and both shifts are valid
+            let rest = len - free;
+            let word = (self.acc << free) | (code >> rest);
+            self.buf.extend_from_slice(&word.to_be_bytes());
+            self.acc = code & ((1 << rest) - 1);
+            self.bits = rest;
+        }
+    }
+
+    /// Number of bits written so far.
+    pub fn len(&self) -> u64 {
+        self.buf.len() as u64 * 8 + u64::from(self.bits)
+    }
+
+    /// Returns the written bytes, the last byte padded with zeros.
+    pub fn finish(self) -> Vec<u8> {
+        let mut buf = self.buf;
+        if self.bits > 0 {
+            let word = self.acc << (64 - self.bits);
+            buf.extend_from_slice(&word.to_be_bytes()[..self.bits.div_ceil(8) as usize]);
+        }
+
+        buf
+    }
+}
+
+/// Encodes `symbols` into payload bytes, the last byte padded with zeros.
+/// Returns the bytes and their size in bits.
+pub fn encode_payload(code_set: &CodeSet, symbols: &[u32]) -> (Vec<u8>, u64) {
+    let mut pending = BitWriter::new();
+    if code_set.contexts.is_empty() {
+        let code_map = &code_set.maps[0];
+        for &symbol in symbols {
+            let (code, len) = code_map.get(symbol);
+            pending.put(code, u32::from(len));
+        }
+    } else {
+        // Every block starts with the shared table
+        let mut table = 0;
+        for &symbol in symbols {
+            let (code, len) = code_set.maps[table].get(symbol);
+            pending.put(code, u32::from(len));
+            table = code_set.contexts.table(symbol);
+        }
+    }
+
+    let bits = pending.len();
+    (pending.finish(), bits)
+}
+
+/// Encodes `symbols` with the codes of `tree`, updating it after every
+/// symbol. Returns the payload bytes and their size in bits.
+///
+/// A symbol seen for the first time is sent as the code of the NYT leaf
+/// followed by the symbol in `adaptive::literal_bits` bits.
+pub fn encode_adaptive_payload(
+    tree: &mut AdaptiveTree,
+    mode: Mode,
+    symbols: &[u32],
+) -> (Vec<u8>, u64) {
+    let mut pending = BitWriter::new();
+    // Codes are found from the leaf up, in chunks of 63 bits since an
+    // adaptive tree is not bounded in depth
+    let mut chunks = Vec::new();
+
+    for &symbol in symbols {
+        let leaf = tree.leaf(symbol);
+        let mut node = leaf.unwrap_or(tree.nyt());
+        let (mut code, mut len) = (0u64, 0u32);
+        while let Some((parent, bit)) = tree.parent(node) {
+            if len == 63 {
+                chunks.push(code);
+                (code, len) = (0, 0);
+            }
+            code |= u64::from(bit) << len;
+            len += 1;
+            node = parent;
+        }
+
+        pending.put(code, len);
+        while let Some(chunk) = chunks.pop() {
+            pending.put(chunk, 63);
+        }
+        if leaf.is_none() {
+            pending.put(u64::from(symbol), adaptive::literal_bits(mode));
+        }
+
+        tree.update(symbol);
+    }
+
+    let bits = pending.len();
+    (pending.finish(), bits)
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
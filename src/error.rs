@@ -0,0 +1,87 @@
+use std::{fmt, io};
+
+/// Errors returned by the encoder and decoder.
+#[derive(Debug)]
+pub enum Error {
+    /// The input does not start with the `CCHF` signature, or the gzip one
+    /// when reading gzip.
+    BadMagic,
+    /// The input was written by a format version this build cannot read.
+    UnsupportedVersion(u8),
+    /// The header sets flags this build does not understand.
+    UnsupportedFlags(u8),
+    /// The header names an entropy coder this build does not have, or a
+    /// gzip compression method other than DEFLATE.
+    UnsupportedCoder(u8),
+    /// The input ended before everything it announced was read.
+    Truncated,
+    /// A text mode symbol is not a Unicode scalar value, or a bytes mode
+    /// symbol is not a byte.
+    InvalidCodepoint(u32),
+    /// The code table is malformed or the payload holds an unknown code.
+    InvalidTree,
+    /// The block structure is malformed.
+    InvalidBlock,
+    /// Random access needs a block index, which the file does not have.
+    MissingIndex,
+    /// Text mode input is not valid UTF-8.
+    InvalidUtf8,
+    /// A block holds more distinct symbols than codes of the requested
+    /// maximum length can tell apart.
+    CodeLengthLimit(u8),
+    /// The decoded size differs from the size stored in the file.
+    LengthMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// The CRC-32 of the decoded content differs from the stored one.
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadMagic => write!(f, "invalid file signature"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported file version {version}"),
+            Error::UnsupportedFlags(flags) => write!(f, "unsupported header flags {flags:#010b}"),
+            Error::UnsupportedCoder(id) => write!(f, "unsupported entropy coder {id}"),
+            Error::Truncated => write!(f, "unexpected end of input"),
+            Error::InvalidCodepoint(symbol) => write!(f, "invalid code point {symbol:#x}"),
+            Error::InvalidTree => write!(f, "invalid code table or code"),
+            Error::InvalidBlock => write!(f, "invalid block"),
+            Error::MissingIndex => write!(f, "file has no block index"),
+            Error::InvalidUtf8 => write!(f, "input is not valid UTF-8"),
+            Error::CodeLengthLimit(limit) => {
+                write!(f, "too many distinct symbols for {limit} bit codes")
+            }
+            Error::LengthMismatch { expected, actual } => write!(
+                f,
+                "decoded {actual} bytes but the file stores {expected} bytes"
+            ),
+            Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch, expected {expected:#010x} but got {actual:#010x}"
+            ),
+            Error::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
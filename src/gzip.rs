@@ -0,0 +1,301 @@
+use std::{
+    io::{Read, Write},
+    time::Instant,
+};
+
+use crate::{crc32::Crc32, encoder::writer::CountingWriter, error::Error, stats::Stats};
+
+mod deflate;
+mod inflate;
+
+use inflate::{BitReader, Output};
+
+/// First bytes of every gzip member.
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Compression method of DEFLATE, the only one gzip defines.
+const METHOD_DEFLATE: u8 = 8;
+/// Header flags telling which optional fields follow the fixed header.
+const FLAG_HCRC: u8 = 0b0000_0010;
+const FLAG_EXTRA: u8 = 0b0000_0100;
+const FLAG_NAME: u8 = 0b0000_1000;
+const FLAG_COMMENT: u8 = 0b0001_0000;
+/// Header flags RFC 1952 reserves, they have to be clear.
+const RESERVED_FLAGS: u8 = 0b1110_0000;
+/// Operating system byte of written headers, for an unknown system.
+const OS_UNKNOWN: u8 = 255;
+
+/// Block types of DEFLATE.
+const BLOCK_STORED: u32 = 0;
+const BLOCK_FIXED: u32 = 1;
+const BLOCK_DYNAMIC: u32 = 2;
+
+/// Ends every block, after the 256 literals.
+const END_OF_BLOCK: usize = 256;
+/// Symbol of the first length code.
+const FIRST_LENGTH: usize = 257;
+/// Number of literal and length codes in use, and of distance codes.
+const LITERAL_CODES: usize = 286;
+const DISTANCE_CODES: usize = 30;
+/// Longest code of the literal, length and distance codes, and of the
+/// codes of their code lengths.
+const MAX_CODE_LEN: u8 = 15;
+const MAX_LENGTH_CODE_LEN: u8 = 7;
+/// Code length codes in the order a dynamic block stores their lengths.
+const LENGTH_CODE_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Code lengths of the literal and length codes and of the distance codes
+/// of fixed blocks. Both cover two codes that never occur.
+fn fixed_lengths() -> ([u8; 288], [u8; 32]) {
+    let mut literals = [8; 288];
+    literals[144..256].fill(9);
+    literals[256..280].fill(7);
+
+    (literals, [5; 32])
+}
+
+/// Compresses everything from `input` into a single gzip member, a DEFLATE
+/// stream as gzip and zlib read it.
+///
+/// Matches come from the match finder of the lz77 coder and reach up to
+/// 32 KiB back, across blocks too. Every block is coded with Huffman codes
+/// of its own, the fixed codes of DEFLATE or stored as is, whichever is
+/// smallest.
+pub fn compress<R: Read, W: Write>(input: &mut R, output: &mut W) -> Result<Stats, Error> {
+    let start = Instant::now();
+    let mut stats = Stats::default();
+    let mut writer = CountingWriter::new(output);
+
+    // No modification time, flags or extra flags
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[METHOD_DEFLATE, 0, 0, 0, 0, 0, 0, OS_UNKNOWN])?;
+
+    let (crc, len) = deflate::deflate(input, &mut writer, &mut stats)?;
+    writer.write_all(&crc.to_le_bytes())?;
+    // The size is stored modulo 2^32
+    writer.write_all(&(len as u32).to_le_bytes())?;
+    writer.flush()?;
+
+    stats.input_bytes = len;
+    stats.output_bytes = writer.count();
+    stats.header_bits = stats.output_bytes * 8 - stats.payload_bits;
+    stats.total_time = start.elapsed();
+
+    Ok(stats)
+}
+
+/// Decompresses every gzip member of `input` into `output`, checking the
+/// CRC-32 and size each member ends with.
+pub fn decompress<R: Read, W: Write>(input: R, output: &mut W) -> Result<Stats, Error> {
+    let start = Instant::now();
+    let mut stats = Stats::default();
+    let mut reader = BitReader::new(input);
+    let mut seen = [false; 256];
+
+    // Concatenated members decode to the concatenation of their content
+    loop {
+        read_header(&mut reader)?;
+
+        let mut out = Output::new(output);
+        inflate::inflate(&mut reader, &mut out, &mut stats)?;
+        let (crc, len) = out.finish(&mut seen)?;
+
+        reader.align();
+        let expected = u32::from_le_bytes(reader.read_array()?);
+        if expected != crc {
+            return Err(Error::ChecksumMismatch {
+                expected,
+                actual: crc,
+            });
+        }
+        let expected = u32::from_le_bytes(reader.read_array()?);
+        if expected != len as u32 {
+            return Err(Error::LengthMismatch {
+                expected: u64::from(expected),
+                actual: len,
+            });
+        }
+        stats.output_bytes += len;
+
+        if reader.is_at_end()? {
+            break;
+        }
+    }
+
+    stats.input_bytes = reader.bytes_consumed();
+    stats.header_bits = stats.input_bytes * 8 - stats.payload_bits;
+    stats.symbols = stats.output_bytes;
+    stats.distinct_symbols = seen.iter().filter(|&&seen| seen).count() as u64;
+    stats.total_time = start.elapsed();
+
+    Ok(stats)
+}
+
+/// Reads the header of a member up to its DEFLATE stream, skipping the
+/// optional fields and checking the header CRC when there is one.
+fn read_header<R: Read>(reader: &mut BitReader<R>) -> Result<(), Error> {
+    let mut crc = Crc32::new();
+    let mut read = |reader: &mut BitReader<R>, bytes: &mut [u8]| {
+        reader.read_bytes(bytes)?;
+        crc.update(bytes);
+        Ok::<_, Error>(())
+    };
+
+    // The first byte tells a member from trailing bytes, even ones too
+    // short to hold a header
+    let mut fixed = [0u8; 10];
+    read(reader, &mut fixed[..1])?;
+    if fixed[0] != MAGIC[0] {
+        return Err(Error::BadMagic);
+    }
+    read(reader, &mut fixed[1..])?;
+    if fixed[..2] != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    if fixed[2] != METHOD_DEFLATE {
+        return Err(Error::UnsupportedCoder(fixed[2]));
+    }
+    let flags = fixed[3];
+    if flags & RESERVED_FLAGS != 0 {
+        return Err(Error::UnsupportedFlags(flags));
+    }
+
+    if flags & FLAG_EXTRA != 0 {
+        let mut len = [0u8; 2];
+        read(reader, &mut len)?;
+        let mut extra = vec![0u8; usize::from(u16::from_le_bytes(len))];
+        read(reader, &mut extra)?;
+    }
+    // The file name and comment end with a zero byte
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let mut byte = [0xff];
+            while byte[0] != 0 {
+                read(reader, &mut byte)?;
+            }
+        }
+    }
+
+    if flags & FLAG_HCRC != 0 {
+        let actual = crc.finish() & 0xffff;
+        let expected = u32::from(u16::from_le_bytes(reader.read_array()?));
+        if expected != actual {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "stored block" in a stored block, as written by zlib.
+    const STORED: [u8; 35] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x0c, 0x00, 0xf3, 0xff,
+        0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x20, 0x62, 0x6c, 0x6f, 0x63, 0x6b, 0x94, 0xa3, 0x24,
+        0x3d, 0x0c, 0x00, 0x00, 0x00,
+    ];
+    /// "hello, hello, hello!" in a block with the fixed codes, with a match.
+    const FIXED: [u8; 30] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0xd7, 0x51, 0xc8, 0x40, 0xa2, 0x14, 0x01, 0xa7, 0xbb, 0xd2, 0xfe, 0x14, 0x00, 0x00, 0x00,
+    ];
+    /// The squares of 0 to 59 followed by commas in a block with dynamic
+    /// codes.
+    const DYNAMIC: [u8; 148] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x15, 0x8e, 0xc1, 0x01, 0xc0,
+        0x30, 0x08, 0x02, 0x17, 0xe2, 0xa1, 0xc6, 0x9a, 0xb8, 0xff, 0x62, 0x3d, 0x5e, 0x24, 0x0a,
+        0x78, 0xa1, 0x54, 0x6b, 0x95, 0xa3, 0xfa, 0x74, 0x46, 0xbd, 0x9a, 0xd6, 0x4b, 0x65, 0x84,
+        0xb2, 0xd0, 0x6e, 0xb6, 0x38, 0x16, 0x0b, 0x9e, 0xfa, 0xd0, 0xb7, 0x3a, 0xd5, 0xf8, 0x49,
+        0xe3, 0xeb, 0x46, 0x5f, 0xeb, 0xab, 0xd5, 0x77, 0x47, 0x83, 0x6f, 0xd0, 0xcb, 0xff, 0x32,
+        0x7f, 0xec, 0x17, 0xdf, 0x8e, 0x7b, 0x09, 0x66, 0xd0, 0x90, 0x49, 0x55, 0xba, 0x33, 0x8b,
+        0xf2, 0x3c, 0xbe, 0xd2, 0x3e, 0xf7, 0xf9, 0xee, 0x18, 0x60, 0x4c, 0x72, 0x21, 0xca, 0xd7,
+        0x66, 0x80, 0xb0, 0xc2, 0x14, 0x69, 0xe2, 0x8a, 0x55, 0x9d, 0x68, 0x55, 0x47, 0x42, 0x46,
+        0xa2, 0xc6, 0xaf, 0xeb, 0xd9, 0xf3, 0x76, 0xf1, 0x1d, 0x27, 0x0e, 0xfd, 0x86, 0x86, 0xfc,
+        0xd0, 0x77, 0x9a, 0xe6, 0x1f, 0x1f, 0xf8, 0x99, 0xdb, 0xfe, 0x00, 0x00, 0x00,
+    ];
+
+    fn decompressed(input: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        decompress(input, &mut out)?;
+        Ok(out)
+    }
+
+    fn squares() -> Vec<u8> {
+        (0..60u32)
+            .flat_map(|i| format!("{},", i * i).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_block_types() {
+        assert_eq!(decompressed(&STORED).unwrap(), b"stored block");
+        assert_eq!(decompressed(&FIXED).unwrap(), b"hello, hello, hello!");
+        assert_eq!(decompressed(&DYNAMIC).unwrap(), squares());
+    }
+
+    #[test]
+    fn test_members() {
+        let input = [&STORED[..], &FIXED, &DYNAMIC].concat();
+        let mut expected = b"stored blockhello, hello, hello!".to_vec();
+        expected.extend(squares());
+
+        assert_eq!(decompressed(&input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_mismatches() {
+        let mut crc = FIXED;
+        crc[FIXED.len() - 8] ^= 1;
+        assert!(matches!(
+            decompressed(&crc),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+
+        let mut len = FIXED;
+        len[FIXED.len() - 4] ^= 1;
+        assert!(matches!(
+            decompressed(&len),
+            Err(Error::LengthMismatch {
+                expected: 21,
+                actual: 20
+            })
+        ));
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        for trailing in [&b"\0"[..], b"junk", b"not a gzip member at all"] {
+            let input = [&FIXED[..], trailing].concat();
+            assert!(matches!(decompressed(&input), Err(Error::BadMagic)));
+        }
+        let truncated = &FIXED[..FIXED.len() - 3];
+        assert!(matches!(decompressed(truncated), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut state = 1u32;
+        let random = (0..50_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        // Matches up to the longest length and distance, and across blocks
+        let mut repeated = random[..40_000].to_vec();
+        repeated.extend_from_within(..40_000);
+        repeated.extend([b'a'; 100_000]);
+
+        for input in [&b""[..], b"a", b"hello, hello, hello!", &random, &repeated] {
+            let mut compressed = Vec::new();
+            compress(&mut &input[..], &mut compressed).unwrap();
+            assert_eq!(decompressed(&compressed).unwrap(), input);
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    time::Instant,
+};
+
+use super::{
+    fixed_lengths, BLOCK_DYNAMIC, BLOCK_FIXED, BLOCK_STORED, DISTANCE_CODES, END_OF_BLOCK,
+    FIRST_LENGTH, LENGTH_CODE_ORDER, LITERAL_CODES, MAX_CODE_LEN, MAX_LENGTH_CODE_LEN,
+};
+use crate::{
+    crc32::Crc32,
+    encoder::huffman,
+    error::Error,
+    lz77::{self, DISTANCE_BASE, LENGTH_BASE, MIN_MATCH, WINDOW_SIZE},
+    shared::canonical_codes,
+    stats::Stats,
+};
+
+/// Input bytes per block.
+const BLOCK_SIZE: usize = 1 << 17;
+/// Most bytes a stored block holds.
+const MAX_STORED: usize = 0xffff;
+
+/// LSB-first bit writer, the bit order of DEFLATE.
+struct BitWriter {
+    buf: Vec<u8>,
+    acc: u64,
+    /// Number of pending bits in the low end of `acc`, always below 8
+    /// between calls.
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            buf: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Appends the low `len` bits of `value`, lowest bit first. `len` is at
+    /// most 32.
+    #[inline]
+    fn put(&mut self, value: u64, len: u32) {
+        self.acc |= value << self.bits;
+        self.bits += len;
+        while self.bits >= 8 {
+            self.buf.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    /// Appends a Huffman code, which DEFLATE sends from its highest bit.
+    #[inline]
+    fn put_code(&mut self, (code, len): (u64, u8)) {
+        self.put(code, u32::from(len));
+    }
+
+    /// Pads the last byte with zeros.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.put(0, 8 - self.bits);
+        }
+    }
+
+    /// Number of bits written so far.
+    fn len(&self) -> u64 {
+        self.buf.len() as u64 * 8 + u64::from(self.bits)
+    }
+
+    /// Takes the whole bytes written so far, keeping the pending bits.
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// Huffman codes of a block, the codes reversed so `put` sends them from
+/// their highest bit.
+struct Codes {
+    literals: Vec<(u64, u8)>,
+    distances: Vec<(u64, u8)>,
+}
+
+impl Codes {
+    fn new(literals: &[u8], distances: &[u8]) -> Self {
+        Codes {
+            literals: reversed_codes(literals),
+            distances: reversed_codes(distances),
+        }
+    }
+}
+
+/// Canonical codes of `lengths`, indexed by symbol with their bits reversed.
+fn reversed_codes(lengths: &[u8]) -> Vec<(u64, u8)> {
+    let mut codes = vec![(0, 0); lengths.len()];
+    for (symbol, (code, len)) in canonical_codes(&length_map(lengths)) {
+        let reversed = code.reverse_bits() >> (64 - u32::from(len));
+        codes[symbol as usize] = (reversed, len);
+    }
+
+    codes
+}
+
+/// The symbols of `lengths` with a code.
+fn length_map(lengths: &[u8]) -> BTreeMap<u32, u8> {
+    lengths
+        .iter()
+        .enumerate()
+        .filter(|&(_, &len)| len > 0)
+        .map(|(symbol, &len)| (symbol as u32, len))
+        .collect()
+}
+
+/// Compresses everything from `input` into a DEFLATE stream on `output`.
+/// Returns the CRC-32 and size of the input.
+pub fn deflate<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    stats: &mut Stats,
+) -> Result<(u32, u64), Error> {
+    let mut crc = Crc32::new();
+    let mut len = 0;
+    let mut seen = [false; 256];
+    let mut writer = BitWriter::new();
+    // End of the previous block, which matches may reach back into
+    let mut history = Vec::new();
+
+    // Reading a block ahead tells whether the current one is the last
+    let mut block = read_block(input)?;
+    loop {
+        let next = read_block(input)?;
+        let last = next.is_empty();
+
+        crc.update(&block);
+        len += block.len() as u64;
+        for &byte in &block {
+            seen[usize::from(byte)] = true;
+        }
+
+        write_block(&mut writer, &history, &block, last, stats)?;
+        output.write_all(&writer.take())?;
+        history.extend_from_slice(&block);
+        history.drain(..history.len().saturating_sub(WINDOW_SIZE));
+
+        if last {
+            break;
+        }
+        block = next;
+    }
+    writer.align();
+    output.write_all(&writer.take())?;
+
+    stats.symbols = len;
+    stats.distinct_symbols = seen.iter().filter(|&&seen| seen).count() as u64;
+
+    Ok((crc.finish(), len))
+}
+
+/// Reads up to `BLOCK_SIZE` bytes, fewer only at the end of the input.
+fn read_block<R: Read>(input: &mut R) -> Result<Vec<u8>, Error> {
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    input.take(BLOCK_SIZE as u64).read_to_end(&mut block)?;
+
+    Ok(block)
+}
+
+/// Writes `block` as a dynamic, fixed or stored block, whichever is
+/// smallest, matches reaching back into `history`.
+fn write_block(
+    writer: &mut BitWriter,
+    history: &[u8],
+    block: &[u8],
+    last: bool,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    let table_start = Instant::now();
+    let symbols = history
+        .iter()
+        .chain(block)
+        .map(|&byte| u32::from(byte))
+        .collect::<Vec<_>>();
+    let tokens = lz77::tokenize_after(&symbols, history.len());
+
+    let (literal_freqs, distance_freqs) = code_frequencies(&tokens);
+    let literals = code_lengths(&literal_freqs, LITERAL_CODES, MAX_CODE_LEN)?;
+    let distances = code_lengths(&distance_freqs, DISTANCE_CODES, MAX_CODE_LEN)?;
+    let header = DynamicHeader::new(&literals, &distances)?;
+
+    let (fixed_literals, fixed_distances) = fixed_lengths();
+    let dynamic_bits =
+        header.bits() + coded_bits(&literal_freqs, &distance_freqs, &literals, &distances);
+    let fixed_bits = coded_bits(
+        &literal_freqs,
+        &distance_freqs,
+        &fixed_literals,
+        &fixed_distances,
+    );
+    // Every stored block pads to a byte and stores its length twice
+    let stored_bits =
+        block.len().div_ceil(MAX_STORED).max(1) as u64 * (3 + 7 + 32) + block.len() as u64 * 8;
+    stats.table_time += table_start.elapsed();
+
+    let payload_start = Instant::now();
+    let bfinal = u64::from(last);
+    if stored_bits < dynamic_bits.min(fixed_bits) {
+        write_stored(writer, block, last, stats);
+    } else {
+        let codes = if dynamic_bits < fixed_bits {
+            writer.put(bfinal | u64::from(BLOCK_DYNAMIC) << 1, 3);
+            header.write(writer);
+            Codes::new(&literals, &distances)
+        } else {
+            writer.put(bfinal | u64::from(BLOCK_FIXED) << 1, 3);
+            Codes::new(&fixed_literals, &fixed_distances)
+        };
+
+        let start = writer.len();
+        write_tokens(writer, &codes, &tokens);
+        stats.payload_bits += writer.len() - start;
+        stats.blocks += 1;
+    }
+    stats.payload_time += payload_start.elapsed();
+
+    Ok(())
+}
+
+/// Writes `block` as stored blocks of at most `MAX_STORED` bytes.
+fn write_stored(writer: &mut BitWriter, block: &[u8], last: bool, stats: &mut Stats) {
+    let mut pieces = block.chunks(MAX_STORED).collect::<Vec<_>>();
+    // An empty block still takes a block, which may end the stream
+    if pieces.is_empty() {
+        pieces.push(&[]);
+    }
+
+    for (i, piece) in pieces.iter().enumerate() {
+        let bfinal = u64::from(last && i == pieces.len() - 1);
+        writer.put(bfinal | u64::from(BLOCK_STORED) << 1, 3);
+        writer.align();
+
+        let len = piece.len() as u16;
+        writer.put(u64::from(len), 16);
+        writer.put(u64::from(!len), 16);
+        for &byte in *piece {
+            writer.put(u64::from(byte), 8);
+        }
+        stats.payload_bits += piece.len() as u64 * 8;
+        stats.blocks += 1;
+    }
+}
+
+/// Writes the codes of `tokens` and the end of the block.
+fn write_tokens(writer: &mut BitWriter, codes: &Codes, tokens: &[u32]) {
+    for &token in tokens {
+        if token < LENGTH_BASE {
+            writer.put_code(codes.literals[token as usize]);
+        } else if token < DISTANCE_BASE {
+            let len = (token - LENGTH_BASE) as usize + MIN_MATCH;
+            let (code, extra_bits, extra) = lz77::code_of(&lz77::LENGTH_CODES, len);
+            writer.put_code(codes.literals[FIRST_LENGTH + code]);
+            writer.put(extra, extra_bits);
+        } else {
+            let dist = (token - DISTANCE_BASE) as usize + 1;
+            let (code, extra_bits, extra) = lz77::code_of(&lz77::DISTANCE_CODES, dist);
+            writer.put_code(codes.distances[code]);
+            writer.put(extra, extra_bits);
+        }
+    }
+    writer.put_code(codes.literals[END_OF_BLOCK]);
+}
+
+/// Frequencies of the literal and length codes, with one end of block, and
+/// of the distance codes.
+fn code_frequencies(tokens: &[u32]) -> (BTreeMap<u32, u32>, BTreeMap<u32, u32>) {
+    let mut literals = BTreeMap::from([(END_OF_BLOCK as u32, 1)]);
+    let mut distances = BTreeMap::new();
+
+    for &token in tokens {
+        let (codes, code) = if token < LENGTH_BASE {
+            (&mut literals, token)
+        } else if token < DISTANCE_BASE {
+            let len = (token - LENGTH_BASE) as usize + MIN_MATCH;
+            let code = lz77::code_of(&lz77::LENGTH_CODES, len).0;
+            (&mut literals, (FIRST_LENGTH + code) as u32)
+        } else {
+            let dist = (token - DISTANCE_BASE) as usize + 1;
+            let code = lz77::code_of(&lz77::DISTANCE_CODES, dist).0;
+            (&mut distances, code as u32)
+        };
+        *codes.entry(code).or_insert(0) += 1;
+    }
+
+    (literals, distances)
+}
+
+/// Code lengths of `symbols` symbols for `freq_table`, at most `max_len`
+/// bits, derived from the Huffman tree of `generate_tree`.
+///
+/// A code needs two symbols to be complete, which some decoders insist on,
+/// so codes of fewer symbols get unused ones added.
+fn code_lengths(
+    freq_table: &BTreeMap<u32, u32>,
+    symbols: usize,
+    max_len: u8,
+) -> Result<Vec<u8>, Error> {
+    let mut freq_table = freq_table.clone();
+    for symbol in 0..2 {
+        if freq_table.len() < 2 {
+            freq_table.entry(symbol).or_insert(1);
+        }
+    }
+
+    let mut lengths = vec![0; symbols];
+    for (symbol, len) in huffman::code_lengths(&freq_table, Some(max_len))? {
+        lengths[symbol as usize] = len;
+    }
+
+    Ok(lengths)
+}
+
+/// Size in bits of the codes and extra bits of a block coded with
+/// `literals` and `distances`, its end included.
+fn coded_bits(
+    literal_freqs: &BTreeMap<u32, u32>,
+    distance_freqs: &BTreeMap<u32, u32>,
+    literals: &[u8],
+    distances: &[u8],
+) -> u64 {
+    let literal_bits = literal_freqs.iter().map(|(&symbol, &freq)| {
+        let extra_bits = match (symbol as usize).checked_sub(FIRST_LENGTH) {
+            Some(code) => lz77::LENGTH_CODES[code].1,
+            None => 0,
+        };
+        u64::from(freq) * u64::from(literals[symbol as usize] + extra_bits)
+    });
+    let distance_bits = distance_freqs.iter().map(|(&code, &freq)| {
+        let extra_bits = lz77::DISTANCE_CODES[code as usize].1;
+        u64::from(freq) * u64::from(distances[code as usize] + extra_bits)
+    });
+
+    literal_bits.chain(distance_bits).sum()
+}
+
+/// Code lengths of a dynamic block, stored as Huffman coded code lengths
+/// with runs of repeated lengths.
+struct DynamicHeader {
+    literal_codes: usize,
+    distance_codes: usize,
+    /// Code lengths of the code length codes.
+    lengths: Vec<u8>,
+    /// Code length codes with the value of their extra bits.
+    symbols: Vec<(usize, u32)>,
+}
+
+impl DynamicHeader {
+    fn new(literals: &[u8], distances: &[u8]) -> Result<Self, Error> {
+        // Trailing symbols without a code are left out
+        let literal_codes = used_codes(literals).max(FIRST_LENGTH);
+        let distance_codes = used_codes(distances).max(1);
+        let mut all = literals[..literal_codes].to_vec();
+        all.extend_from_slice(&distances[..distance_codes]);
+
+        let symbols = run_lengths(&all);
+        let mut freq_table = BTreeMap::new();
+        for &(symbol, _) in &symbols {
+            *freq_table.entry(symbol as u32).or_insert(0) += 1;
+        }
+        let lengths = code_lengths(&freq_table, LENGTH_CODE_ORDER.len(), MAX_LENGTH_CODE_LEN)?;
+
+        Ok(DynamicHeader {
+            literal_codes,
+            distance_codes,
+            lengths,
+            symbols,
+        })
+    }
+
+    /// Number of code length codes stored, trailing ones in storage order
+    /// without a code are left out.
+    fn length_codes(&self) -> usize {
+        let used = LENGTH_CODE_ORDER
+            .iter()
+            .rposition(|&code| self.lengths[code] > 0)
+            .map_or(0, |pos| pos + 1);
+        used.max(4)
+    }
+
+    /// Size of the header in bits, without the block type.
+    fn bits(&self) -> u64 {
+        let symbols = self
+            .symbols
+            .iter()
+            .map(|&(symbol, _)| u64::from(u32::from(self.lengths[symbol]) + extra_bits(symbol)))
+            .sum::<u64>();
+        5 + 5 + 4 + 3 * self.length_codes() as u64 + symbols
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        let length_codes = self.length_codes();
+        writer.put((self.literal_codes - FIRST_LENGTH) as u64, 5);
+        writer.put(self.distance_codes as u64 - 1, 5);
+        writer.put(length_codes as u64 - 4, 4);
+        for &code in &LENGTH_CODE_ORDER[..length_codes] {
+            writer.put(u64::from(self.lengths[code]), 3);
+        }
+
+        let codes = reversed_codes(&self.lengths);
+        for &(symbol, extra) in &self.symbols {
+            writer.put_code(codes[symbol]);
+            writer.put(u64::from(extra), extra_bits(symbol));
+        }
+    }
+}
+
+/// Number of symbols up to the last one with a code.
+fn used_codes(lengths: &[u8]) -> usize {
+    lengths
+        .iter()
+        .rposition(|&len| len > 0)
+        .map_or(0, |pos| pos + 1)
+}
+
+/// Extra bits of code length code `symbol`.
+fn extra_bits(symbol: usize) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+/// Turns code lengths into code length codes: lengths as themselves, `16`
+/// repeating the previous length 3 to 6 times, `17` and `18` runs of 3 to 10
+/// and 11 to 138 zeros.
+fn run_lengths(lengths: &[u8]) -> Vec<(usize, u32)> {
+    let mut symbols = Vec::new();
+
+    for run in lengths.chunk_by(|a, b| a == b) {
+        let len = usize::from(run[0]);
+        let mut left = run.len();
+        if len == 0 {
+            while left >= 11 {
+                let n = left.min(138);
+                symbols.push((18, (n - 11) as u32));
+                left -= n;
+            }
+            if left >= 3 {
+                symbols.push((17, (left - 3) as u32));
+                left = 0;
+            }
+        } else {
+            symbols.push((len, 0));
+            left -= 1;
+            while left >= 3 {
+                let n = left.min(6);
+                symbols.push((16, (n - 3) as u32));
+                left -= n;
+            }
+        }
+        symbols.extend(std::iter::repeat_n((len, 0), left));
+    }
+
+    symbols
+}
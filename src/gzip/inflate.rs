@@ -0,0 +1,437 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read, Write},
+    time::Instant,
+};
+
+use super::{
+    fixed_lengths, BLOCK_DYNAMIC, BLOCK_FIXED, BLOCK_STORED, DISTANCE_CODES, END_OF_BLOCK,
+    FIRST_LENGTH, LENGTH_CODE_ORDER, LITERAL_CODES,
+};
+use crate::{
+    crc32::Crc32,
+    error::Error,
+    lz77::{self, Window},
+    shared::canonical_codes,
+    stats::Stats,
+};
+
+/// Size of the buffer refilled from the underlying reader.
+const BUFFER_SIZE: usize = 64 * 1024;
+/// Decoded output is handed to the writer in chunks of this size.
+const OUTPUT_CHUNK: usize = 64 * 1024;
+
+/// Buffered LSB-first bit reader, the bit order of DEFLATE.
+///
+/// Reading past the end of the input yields zero bits, every read checks
+/// afterwards that the bits it consumed were there.
+pub struct BitReader<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+    bits: u64,
+    count: u32,
+    /// Bits consumed so far
+    position: u64,
+    /// Bytes pulled from `inner` so far
+    fetched: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R) -> Self {
+        BitReader {
+            inner,
+            buf: vec![0; BUFFER_SIZE],
+            pos: 0,
+            end: 0,
+            bits: 0,
+            count: 0,
+            position: 0,
+            fetched: 0,
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), Error> {
+        self.end = loop {
+            match self.inner.read(&mut self.buf) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        self.pos = 0;
+        self.fetched += self.end as u64;
+
+        Ok(())
+    }
+
+    /// Tops the bit buffer up to at least 57 bits.
+    #[inline]
+    fn refill(&mut self) -> Result<(), Error> {
+        while self.count <= 56 {
+            if self.pos == self.end {
+                self.fill()?;
+            }
+            let byte = if self.pos < self.end {
+                self.pos += 1;
+                self.buf[self.pos - 1]
+            } else {
+                0
+            };
+            self.bits |= u64::from(byte) << self.count;
+            self.count += 8;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next `n` bits without consuming them, the first one in
+    /// the lowest bit. The buffer must have been refilled.
+    #[inline]
+    fn peek(&self, n: u32) -> u64 {
+        self.bits & ((1 << n) - 1)
+    }
+
+    /// Consumes `n` bits, failing when the input ended before them.
+    #[inline]
+    fn consume(&mut self, n: u32) -> Result<(), Error> {
+        self.bits >>= n;
+        self.count -= n;
+        self.position += u64::from(n);
+        if self.position > self.fetched * 8 {
+            return Err(Error::Truncated);
+        }
+
+        Ok(())
+    }
+
+    /// Reads an `n` bit number, `n` at most 32.
+    pub fn read_bits(&mut self, n: u32) -> Result<u32, Error> {
+        self.refill()?;
+        let value = self.peek(n) as u32;
+        self.consume(n)?;
+
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary.
+    pub fn align(&mut self) {
+        // The rest of a partially consumed byte is always buffered
+        let rest = self.count % 8;
+        self.bits >>= rest;
+        self.count -= rest;
+        self.position += u64::from(rest);
+    }
+
+    /// Reads whole bytes, the reader must be byte aligned.
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        debug_assert_eq!(self.position % 8, 0);
+
+        for byte in out.iter_mut() {
+            *byte = self.read_bits(8)? as u8;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut bytes = [0u8; N];
+        self.read_bytes(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    /// Whether every byte of the input was consumed, the reader must be
+    /// byte aligned.
+    pub fn is_at_end(&mut self) -> Result<bool, Error> {
+        self.refill()?;
+        Ok(self.position >= self.fetched * 8)
+    }
+
+    /// Number of whole bytes consumed so far.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.position.div_ceil(8)
+    }
+
+    /// Number of bits consumed so far.
+    fn bits_consumed(&self) -> u64 {
+        self.position
+    }
+}
+
+/// Canonical Huffman decoder of a DEFLATE code.
+///
+/// Codes are assigned from the code lengths with `canonical_codes`, which
+/// orders them the same way DEFLATE does. The table is indexed by the next
+/// `max_len` bits, which come first bit lowest, so every code fills the
+/// entries starting with its bits reversed.
+struct HuffmanDecoder {
+    max_len: u32,
+    /// Symbol and code length of every entry, a length of 0 marks bits no
+    /// code starts with.
+    entries: Vec<(u16, u8)>,
+}
+
+impl HuffmanDecoder {
+    /// Builds the decoder for the code lengths of every symbol, a length of
+    /// 0 meaning the symbol has no code.
+    fn new(lengths: &[u8]) -> Result<Self, Error> {
+        let lengths = lengths
+            .iter()
+            .enumerate()
+            .filter(|&(_, &len)| len > 0)
+            .map(|(symbol, &len)| (symbol as u32, len))
+            .collect::<BTreeMap<_, _>>();
+        let max_len = lengths.values().copied().max().map_or(0, u32::from);
+
+        // Incomplete codes are accepted, their missing codes fail to decode
+        let used = lengths
+            .values()
+            .map(|&len| 1u64 << (max_len - u32::from(len)))
+            .sum::<u64>();
+        if used > 1 << max_len {
+            return Err(Error::InvalidTree);
+        }
+
+        let mut entries = vec![(0, 0); 1 << max_len];
+        for (symbol, (code, len)) in canonical_codes(&lengths) {
+            let first = (code as u32).reverse_bits() >> (32 - u32::from(len));
+            for entry in entries[first as usize..].iter_mut().step_by(1 << len) {
+                *entry = (symbol as u16, len);
+            }
+        }
+
+        Ok(HuffmanDecoder { max_len, entries })
+    }
+
+    #[inline]
+    fn read_symbol<R: Read>(&self, reader: &mut BitReader<R>) -> Result<usize, Error> {
+        reader.refill()?;
+        let (symbol, len) = self.entries[reader.peek(self.max_len) as usize];
+        if len == 0 {
+            return Err(Error::InvalidTree);
+        }
+        reader.consume(u32::from(len))?;
+
+        Ok(usize::from(symbol))
+    }
+}
+
+/// Sink for the decoded bytes of a member, keeping the window matches copy
+/// from and the CRC-32 and size of the content.
+pub struct Output<'a, W: Write> {
+    out: &'a mut W,
+    buf: Vec<u8>,
+    window: Window,
+    crc: Crc32,
+    written: u64,
+    /// Byte values decoded so far.
+    seen: [bool; 256],
+}
+
+impl<'a, W: Write> Output<'a, W> {
+    pub fn new(out: &'a mut W) -> Self {
+        Output {
+            out,
+            buf: Vec::with_capacity(OUTPUT_CHUNK),
+            window: Window::new(),
+            crc: Crc32::new(),
+            written: 0,
+            seen: [false; 256],
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, byte: u8) -> Result<(), Error> {
+        self.buf.push(byte);
+        self.window.push(u32::from(byte));
+        if self.buf.len() >= OUTPUT_CHUNK {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends `len` bytes copied from `dist` bytes back, the copy may
+    /// overlap the bytes it appends.
+    #[inline]
+    fn copy(&mut self, len: usize, dist: usize) -> Result<(), Error> {
+        for _ in 0..len {
+            let byte = self.window.get(dist).ok_or(Error::InvalidBlock)?;
+            self.push(byte as u8)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.out.write_all(&self.buf)?;
+        self.crc.update(&self.buf);
+        for &byte in &self.buf {
+            self.seen[usize::from(byte)] = true;
+        }
+        self.written += self.buf.len() as u64;
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    /// Writes out the rest of the content and returns its CRC-32 and size,
+    /// adding the byte values it holds to `seen`.
+    pub fn finish(mut self, seen: &mut [bool; 256]) -> Result<(u32, u64), Error> {
+        self.flush()?;
+        for (seen, &here) in seen.iter_mut().zip(&self.seen) {
+            *seen |= here;
+        }
+
+        Ok((self.crc.finish(), self.written))
+    }
+}
+
+/// Decodes a DEFLATE stream into `out`, up to the end of its last block.
+pub fn inflate<R: Read, W: Write>(
+    reader: &mut BitReader<R>,
+    out: &mut Output<W>,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    loop {
+        let last = reader.read_bits(1)? == 1;
+
+        let table_start = Instant::now();
+        let tables = match reader.read_bits(2)? {
+            BLOCK_STORED => None,
+            BLOCK_FIXED => {
+                let (literals, distances) = fixed_lengths();
+                Some((
+                    HuffmanDecoder::new(&literals)?,
+                    HuffmanDecoder::new(&distances)?,
+                ))
+            }
+            BLOCK_DYNAMIC => Some(read_tables(reader)?),
+            _ => return Err(Error::InvalidBlock),
+        };
+        stats.table_time += table_start.elapsed();
+
+        let payload_start = Instant::now();
+        let position = reader.bits_consumed();
+        match tables {
+            Some((literals, distances)) => decode_block(reader, &literals, &distances, out)?,
+            None => copy_stored(reader, out)?,
+        }
+        stats.payload_bits += reader.bits_consumed() - position;
+        stats.payload_time += payload_start.elapsed();
+        stats.blocks += 1;
+
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Copies a stored block, its length and the complement of its length
+/// followed by its bytes.
+fn copy_stored<R: Read, W: Write>(
+    reader: &mut BitReader<R>,
+    out: &mut Output<W>,
+) -> Result<(), Error> {
+    reader.align();
+    let len = u16::from_le_bytes(reader.read_array()?);
+    let complement = u16::from_le_bytes(reader.read_array()?);
+    if len != !complement {
+        return Err(Error::InvalidBlock);
+    }
+
+    for _ in 0..len {
+        let [byte] = reader.read_array()?;
+        out.push(byte)?;
+    }
+
+    Ok(())
+}
+
+/// Reads the code lengths of a dynamic block, themselves Huffman coded with
+/// runs of repeated lengths, and builds its decoders.
+fn read_tables<R: Read>(
+    reader: &mut BitReader<R>,
+) -> Result<(HuffmanDecoder, HuffmanDecoder), Error> {
+    let literal_codes = reader.read_bits(5)? as usize + FIRST_LENGTH;
+    let distance_codes = reader.read_bits(5)? as usize + 1;
+    let length_codes = reader.read_bits(4)? as usize + 4;
+    if literal_codes > LITERAL_CODES || distance_codes > DISTANCE_CODES {
+        return Err(Error::InvalidTree);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &code in &LENGTH_CODE_ORDER[..length_codes] {
+        code_lengths[code] = reader.read_bits(3)? as u8;
+    }
+    let length_decoder = HuffmanDecoder::new(&code_lengths)?;
+
+    // Runs may carry on from the literal lengths into the distance lengths
+    let total = literal_codes + distance_codes;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let (len, repeat) = match length_decoder.read_symbol(reader)? {
+            len @ 0..=15 => (len as u8, 1),
+            16 => {
+                let &prev = lengths.last().ok_or(Error::InvalidTree)?;
+                (prev, 3 + reader.read_bits(2)?)
+            }
+            17 => (0, 3 + reader.read_bits(3)?),
+            _ => (0, 11 + reader.read_bits(7)?),
+        };
+        if lengths.len() + repeat as usize > total {
+            return Err(Error::InvalidTree);
+        }
+        lengths.resize(lengths.len() + repeat as usize, len);
+    }
+
+    // A block without an end has no way to stop
+    if lengths[END_OF_BLOCK] == 0 {
+        return Err(Error::InvalidTree);
+    }
+
+    Ok((
+        HuffmanDecoder::new(&lengths[..literal_codes])?,
+        HuffmanDecoder::new(&lengths[literal_codes..])?,
+    ))
+}
+
+/// Decodes the literals and matches of a Huffman coded block up to its end.
+fn decode_block<R: Read, W: Write>(
+    reader: &mut BitReader<R>,
+    literals: &HuffmanDecoder,
+    distances: &HuffmanDecoder,
+    out: &mut Output<W>,
+) -> Result<(), Error> {
+    loop {
+        let symbol = literals.read_symbol(reader)?;
+        if symbol < END_OF_BLOCK {
+            out.push(symbol as u8)?;
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let len = read_extra(reader, &lz77::LENGTH_CODES, symbol - FIRST_LENGTH)?;
+        let code = distances.read_symbol(reader)?;
+        let dist = read_extra(reader, &lz77::DISTANCE_CODES, code)?;
+        out.copy(len, dist)?;
+    }
+}
+
+/// Reads the extra bits after code `code` of `codes`, returning the length
+/// or distance they give.
+#[inline]
+fn read_extra<R: Read>(
+    reader: &mut BitReader<R>,
+    codes: &[(u16, u8)],
+    code: usize,
+) -> Result<usize, Error> {
+    let &(base, extra_bits) = codes.get(code).ok_or(Error::InvalidBlock)?;
+    let extra = reader.read_bits(u32::from(extra_bits))?;
+
+    Ok(usize::from(base) + extra as usize)
+}
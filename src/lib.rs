@@ -1,21 +1,404 @@
-use std::{error::Error, fs};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, IsTerminal, Read, Write},
+    path::Path,
+};
+mod adaptive;
+mod bwt;
+pub mod cli;
+mod coder;
+mod context;
+mod crc32;
 pub mod decoder;
 mod encoder;
+mod error;
+pub mod gzip;
+mod lz77;
+mod parallel;
+mod rle;
 mod shared;
+mod stats;
 
-pub fn run(input_path: &str, out_path: Option<&str>) -> Result<(), Box<dyn Error>> {
-    match out_path {
-        Some(out) => {
-            let input = fs::read_to_string(input_path)?;
-            encoder::encode(&input, out)?;
+use cli::{Command, Config, GZIP_SUFFIX, SUFFIX};
+pub use coder::Coder;
+pub use decoder::Decoder;
+pub use encoder::{encode, Encoder};
+pub use error::Error;
+pub use shared::Mode;
+pub use stats::Stats;
+
+/// Runs `config.command` on every input. A failing input is reported on
+/// stderr and does not stop the others, the first error is returned once all
+/// inputs were processed.
+pub fn run(config: &Config) -> Result<(), Error> {
+    let mut first_error = None;
+
+    if cfg!(not(feature = "parallel")) && config.threads > 1 && !config.quiet {
+        eprintln!("cchuff: built without the parallel feature, using a single thread");
+    }
+
+    for input in &config.inputs {
+        let result = match config.command {
+            Command::Compress => compress(config, input),
+            Command::Decompress => decompress(config, input),
+            Command::Test => test(config, input),
+            Command::Info => info(input),
+        };
+
+        if let Err(e) = result {
+            eprintln!("cchuff: {input}: {e}");
+            first_error.get_or_insert(e);
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Path standing for stdin as an input and stdout as an output.
+const STDIO: &str = "-";
+
+/// Where the result of a command goes.
+enum Target {
+    Stdout,
+    File(String),
+}
+
+impl Target {
+    /// Picks the target of `input_path`, `default` naming the output file
+    /// when none was given. Fails when the output file is the input itself,
+    /// which would be truncated before it is read.
+    fn select(config: &Config, input_path: &str, default: Option<String>) -> Result<Target, Error> {
+        let target = match &config.output {
+            Some(out) if out == STDIO => Target::Stdout,
+            Some(out) => Target::File(out.clone()),
+            None if config.stdout || input_path == STDIO => Target::Stdout,
+            None => default.map(Target::File).ok_or_else(|| {
+                Error::Io(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "unknown suffix, use --output to name the result",
+                ))
+            })?,
+        };
+
+        if let Target::File(out_path) = &target {
+            // An output that does not exist yet cannot be the input
+            if input_path != STDIO
+                && fs::canonicalize(out_path)
+                    .is_ok_and(|out| fs::canonicalize(input_path).is_ok_and(|input| input == out))
+            {
+                return Err(Error::Io(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{out_path} is the input file, use --output to name another one"),
+                )));
+            }
+        }
+
+        Ok(target)
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Target::Stdout => "stdout",
+            Target::File(path) => path,
+        }
+    }
+}
+
+fn open_input(input_path: &str) -> Result<Box<dyn Read>, Error> {
+    if input_path == STDIO {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(File::open(input_path)?))
+    }
+}
+
+fn compress(config: &Config, input_path: &str) -> Result<(), Error> {
+    let suffix = suffix(config);
+    if input_path.ends_with(suffix) && !config.force {
+        if !config.quiet {
+            eprintln!("cchuff: {input_path}: already has {suffix} suffix, skipping");
+        }
+        return Ok(());
+    }
+
+    let target = Target::select(config, input_path, Some(format!("{input_path}{suffix}")))?;
+    if matches!(target, Target::Stdout) && io::stdout().is_terminal() && !config.force {
+        return Err(Error::Io(io::Error::new(
+            ErrorKind::InvalidInput,
+            "compressed data not written to a terminal, use --force to write it anyway",
+        )));
+    }
+
+    // Detecting text needs a full pass, stdin is compressed as bytes so it
+    // can be streamed without spooling it first. gzip has no text mode.
+    let mode = if input_path == STDIO || config.gzip {
+        Mode::Bytes
+    } else {
+        encoder::detect_mode(File::open(input_path)?)?
+    };
+
+    let mut input = open_input(input_path)?;
+    let stats = write_output(config, &target, |mut out| {
+        if config.gzip {
+            return gzip::compress(&mut input, &mut out);
+        }
+        let mut encoder = Encoder::new(out, mode)
+            .with_coder(config.coder)
+            .with_context(config.context)
+            .with_adaptive(config.adaptive)
+            .with_rle(config.rle);
+        if let Some(max_code_len) = config.max_code_len {
+            encoder = encoder.with_max_code_len(max_code_len);
+        }
+        #[cfg(feature = "parallel")]
+        {
+            encoder = encoder.with_threads(config.threads);
+        }
+        encoder.encode(&mut input)
+    })?;
+
+    finish(
+        config,
+        input_path,
+        &target,
+        &stats,
+        stats.input_bytes,
+        stats.output_bytes,
+    )
+}
+
+fn decompress(config: &Config, input_path: &str) -> Result<(), Error> {
+    let stem = input_path
+        .strip_suffix(suffix(config))
+        .filter(|stem| !stem.is_empty())
+        .map(str::to_string);
+    let target = Target::select(config, input_path, stem)?;
+
+    let input = open_input(input_path)?;
+    let stats = write_output(config, &target, |mut out| {
+        if config.gzip {
+            return gzip::decompress(input, &mut out);
+        }
+        decoder(config, input).decode(&mut out)
+    })?;
+
+    finish(
+        config,
+        input_path,
+        &target,
+        &stats,
+        stats.output_bytes,
+        stats.input_bytes,
+    )
+}
+
+/// Suffix of the files written by compress and read by decompress.
+fn suffix(config: &Config) -> &'static str {
+    if config.gzip {
+        GZIP_SUFFIX
+    } else {
+        SUFFIX
+    }
+}
+
+fn decoder(config: &Config, input: Box<dyn Read>) -> Decoder<Box<dyn Read>> {
+    let decoder = Decoder::new(input);
+    #[cfg(feature = "parallel")]
+    let decoder = decoder.with_threads(config.threads);
+    #[cfg(not(feature = "parallel"))]
+    let _ = config;
+
+    decoder
+}
+
+fn test(config: &Config, input_path: &str) -> Result<(), Error> {
+    let input = open_input(input_path)?;
+    let stats = if config.gzip {
+        gzip::decompress(input, &mut io::sink())?
+    } else {
+        decoder(config, input).decode(&mut io::sink())?
+    };
+
+    if config.verbose {
+        eprintln!("{input_path}: OK");
+    }
+    report_stats(config, input_path, &stats);
+
+    Ok(())
+}
+
+fn info(input_path: &str) -> Result<(), Error> {
+    let input = open_input(input_path)?;
+    let info = Decoder::new(input).info()?;
+
+    let mode = match info.mode {
+        Mode::Text => "text",
+        Mode::Bytes => "bytes",
+    };
+    let checksums = match (info.checksum, info.block_checksums) {
+        (true, true) => "content and block CRC-32",
+        (true, false) => "content CRC-32",
+        (false, true) => "block CRC-32",
+        (false, false) => "none",
+    };
+
+    println!("{input_path}:");
+    println!("  version:     {}", info.version);
+    println!("  mode:        {mode}");
+    if info.rle {
+        println!("  coder:       {} with run lengths", info.coder.name());
+    } else {
+        println!("  coder:       {}", info.coder.name());
+    }
+    if info.adaptive {
+        println!("  blocks:      {} (adaptive)", info.blocks);
+    } else if info.context {
+        println!(
+            "  blocks:      {} ({} with contexts)",
+            info.blocks,
+            plural(info.tables, "table")
+        );
+    } else {
+        println!(
+            "  blocks:      {} ({})",
+            info.blocks,
+            plural(info.tables, "table")
+        );
+    }
+    println!("  checksums:   {checksums}");
+    println!(
+        "  index:       {}",
+        if info.block_index { "yes" } else { "no" }
+    );
+    if info.coder.huffman_tables() {
+        match info.max_code_len {
+            Some(len) => println!("  max code:    {len} bits"),
+            None => println!("  max code:    unlimited"),
         }
-        None => {
-            let input = fs::read(input_path)?;
-            let output = decoder::decode(input)?;
+    }
+    println!("  compressed:  {} bytes", info.compressed_len);
+    match info.original_len {
+        Some(len) => println!(
+            "  original:    {len} bytes ({:.1}%)",
+            ratio(len, info.compressed_len)
+        ),
+        None => println!("  original:    unknown"),
+    }
 
-            fs::write(&format!("{input_path}_ext.txt"), output)?;
+    Ok(())
+}
+
+/// Runs `write` against `target` and returns its statistics. A file left
+/// behind by a failed run is removed.
+fn write_output(
+    config: &Config,
+    target: &Target,
+    write: impl FnOnce(&mut dyn Write) -> Result<Stats, Error>,
+) -> Result<Stats, Error> {
+    let out_path = match target {
+        Target::Stdout => {
+            let mut out = io::stdout().lock();
+            let stats = write(&mut out)?;
+            out.flush()?;
+            return Ok(stats);
         }
+        Target::File(out_path) => out_path,
+    };
+
+    if !config.force && Path::new(out_path).exists() {
+        return Err(Error::Io(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{out_path} already exists, use --force to overwrite it"),
+        )));
     }
 
+    let mut out = BufWriter::new(File::create(out_path)?);
+    let result = write(&mut out).and_then(|stats| Ok(out.flush().map(|()| stats)?));
+    if result.is_err() {
+        let _ = fs::remove_file(out_path);
+    }
+
+    result
+}
+
+/// Removes the input unless asked to keep it and reports the result.
+fn finish(
+    config: &Config,
+    input_path: &str,
+    target: &Target,
+    stats: &Stats,
+    original_len: u64,
+    compressed_len: u64,
+) -> Result<(), Error> {
+    if !config.keep && input_path != STDIO && matches!(target, Target::File(_)) {
+        fs::remove_file(input_path)?;
+    }
+
+    if config.verbose {
+        eprintln!(
+            "{input_path}: {:.1}% -- {}",
+            ratio(original_len, compressed_len),
+            target.name()
+        );
+    }
+    report_stats(config, input_path, stats);
+
     Ok(())
 }
+
+/// Prints `stats` on stderr when asked to, stdout may carry the output.
+fn report_stats(config: &Config, input_path: &str, stats: &Stats) {
+    if config.json {
+        eprintln!("{}", stats.to_json());
+    } else if config.stats {
+        eprintln!("{input_path}:\n{stats}");
+    }
+}
+
+/// `count` followed by `noun`, in the plural unless `count` is 1.
+fn plural(count: u64, noun: &str) -> String {
+    if count == 1 {
+        format!("{count} {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
+
+/// Compressed size as a percentage of the original size.
+fn ratio(original_len: u64, compressed_len: u64) -> f64 {
+    if original_len == 0 {
+        return 0.0;
+    }
+    compressed_len as f64 * 100.0 / original_len as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_is_input() {
+        let dir = std::env::temp_dir().join(format!("cchuff-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("a.txt").to_str().unwrap().to_string();
+        fs::write(&input, "hello").unwrap();
+
+        let mut config = Config::build(["-f".to_string(), input.clone()].into_iter())
+            .unwrap()
+            .unwrap();
+        for output in [input.clone(), format!("{}/./a.txt", dir.to_str().unwrap())] {
+            config.output = Some(output);
+            assert!(Target::select(&config, &input, None).is_err());
+            assert!(compress(&config, &input).is_err());
+        }
+        assert_eq!(fs::read_to_string(&input).unwrap(), "hello");
+
+        config.output = Some(format!("{input}.cchf"));
+        assert!(Target::select(&config, &input, None).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
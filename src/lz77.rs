@@ -0,0 +1,289 @@
+use crate::shared::TOKEN_BASE;
+
+/// Shortest and longest match, and the farthest a match may reach back.
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
+pub const WINDOW_SIZE: usize = 1 << 15;
+
+/// Tokens of a match: `LENGTH_BASE + len - MIN_MATCH` followed by
+/// `DISTANCE_BASE + dist - 1`. Every symbol below `LENGTH_BASE` is a literal.
+pub const LENGTH_BASE: u32 = TOKEN_BASE;
+pub const DISTANCE_BASE: u32 = LENGTH_BASE + (MAX_MATCH - MIN_MATCH + 1) as u32;
+
+/// Shortest length of every length code and the number of extra bits
+/// telling lengths of the same code apart, as in DEFLATE.
+pub const LENGTH_CODES: [(u16, u8); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+/// Shortest distance of every distance code and its number of extra bits,
+/// as in DEFLATE.
+pub const DISTANCE_CODES: [(u16, u8); 30] = [
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 1),
+    (7, 1),
+    (9, 2),
+    (13, 2),
+    (17, 3),
+    (25, 3),
+    (33, 4),
+    (49, 4),
+    (65, 5),
+    (97, 5),
+    (129, 6),
+    (193, 6),
+    (257, 7),
+    (385, 7),
+    (513, 8),
+    (769, 8),
+    (1025, 9),
+    (1537, 9),
+    (2049, 10),
+    (3073, 10),
+    (4097, 11),
+    (6145, 11),
+    (8193, 12),
+    (12289, 12),
+    (16385, 13),
+    (24577, 13),
+];
+
+/// Code of `value` in `codes`, with its number of extra bits and their value.
+#[inline]
+pub fn code_of(codes: &[(u16, u8)], value: usize) -> (usize, u32, u64) {
+    let code = codes.partition_point(|&(base, _)| usize::from(base) <= value) - 1;
+    let (base, extra_bits) = codes[code];
+    (
+        code,
+        u32::from(extra_bits),
+        (value - usize::from(base)) as u64,
+    )
+}
+
+/// Bits of the hash heading the chains of positions.
+const HASH_BITS: u32 = 15;
+/// Most earlier positions compared before settling for the longest match
+/// found so far.
+const MAX_CHAIN: usize = 128;
+/// Matches this long end the search.
+const NICE_MATCH: usize = 128;
+/// Matches this long are taken without looking for a longer one at the next
+/// position.
+const LAZY_MATCH: usize = 32;
+/// Shortest matches are left as literals further back than this, their
+/// distance costs more than the literals.
+const FAR_MIN_MATCH: usize = 4096;
+/// Marks the end of a chain.
+const NONE: u32 = u32::MAX;
+
+/// Turns `symbols` into literals and matches.
+///
+/// Positions are chained by the hash of the next `MIN_MATCH` symbols, the
+/// chain of a position holding the earlier ones with the same hash, most
+/// recent first. Matching is lazy: a match is only taken when the next
+/// position does not start a longer one.
+pub fn tokenize(symbols: &[u32]) -> Vec<u32> {
+    tokenize_after(symbols, 0)
+}
+
+/// Turns `symbols[start..]` into literals and matches as `tokenize` does,
+/// matches reaching back into the symbols before `start`.
+pub fn tokenize_after(symbols: &[u32], start: usize) -> Vec<u32> {
+    let mut finder = MatchFinder::new(symbols);
+    for pos in 0..start {
+        finder.insert(pos);
+    }
+    let mut tokens = Vec::with_capacity((symbols.len() - start) / 2);
+    let push_match = |tokens: &mut Vec<u32>, len: usize, dist: usize| {
+        tokens.push(LENGTH_BASE + (len - MIN_MATCH) as u32);
+        tokens.push(DISTANCE_BASE + (dist - 1) as u32);
+    };
+
+    // Match starting at the position before `pos`, not taken yet
+    let mut pending: Option<(usize, usize)> = None;
+    let mut pos = start;
+    while pos < symbols.len() {
+        let found = match pending {
+            Some((len, _)) if len >= LAZY_MATCH => None,
+            Some((len, _)) => finder.find(pos, len),
+            None => finder.find(pos, MIN_MATCH - 1),
+        };
+        finder.insert(pos);
+
+        match (pending, found) {
+            (Some(_), Some(found)) => {
+                tokens.push(symbols[pos - 1]);
+                pending = Some(found);
+                pos += 1;
+            }
+            (Some((len, dist)), None) => {
+                push_match(&mut tokens, len, dist);
+                let end = pos - 1 + len;
+                for skipped in pos + 1..end {
+                    finder.insert(skipped);
+                }
+                pending = None;
+                pos = end;
+            }
+            (None, Some(found)) => {
+                pending = Some(found);
+                pos += 1;
+            }
+            (None, None) => {
+                tokens.push(symbols[pos]);
+                pos += 1;
+            }
+        }
+    }
+    if let Some((len, dist)) = pending {
+        push_match(&mut tokens, len, dist);
+    }
+
+    tokens
+}
+
+struct MatchFinder<'a> {
+    symbols: &'a [u32],
+    /// Latest position of every hash.
+    head: Vec<u32>,
+    /// Previous position with the same hash, indexed by position modulo the
+    /// window size.
+    prev: Vec<u32>,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(symbols: &'a [u32]) -> Self {
+        MatchFinder {
+            symbols,
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW_SIZE],
+        }
+    }
+
+    #[inline]
+    fn hash(&self, pos: usize) -> usize {
+        let s = &self.symbols[pos..pos + MIN_MATCH];
+        let h = s[0].wrapping_mul(0x9e37_79b1)
+            ^ s[1].wrapping_mul(0x85eb_ca77)
+            ^ s[2].wrapping_mul(0xc2b2_ae3d);
+        (h >> (32 - HASH_BITS)) as usize
+    }
+
+    /// Adds `pos` to the chain of its hash.
+    #[inline]
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH > self.symbols.len() {
+            return;
+        }
+        let hash = self.hash(pos);
+        self.prev[pos % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = pos as u32;
+    }
+
+    /// Longest match at `pos` longer than `best_len`, as (length, distance).
+    fn find(&self, pos: usize, mut best_len: usize) -> Option<(usize, usize)> {
+        let symbols = self.symbols;
+        if pos + MIN_MATCH > symbols.len() {
+            return None;
+        }
+        let max_len = MAX_MATCH.min(symbols.len() - pos);
+        if best_len >= max_len {
+            return None;
+        }
+
+        let mut best = None;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NONE {
+                break;
+            }
+            let start = candidate as usize;
+            let dist = pos - start;
+            if dist > WINDOW_SIZE {
+                break;
+            }
+
+            // A longer match has to agree past the best length first
+            if symbols[start + best_len] == symbols[pos + best_len] {
+                let len = symbols[start..start + max_len]
+                    .iter()
+                    .zip(&symbols[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len && (len > MIN_MATCH || dist <= FAR_MIN_MATCH) {
+                    best_len = len;
+                    best = Some((len, dist));
+                    if len >= NICE_MATCH.min(max_len) {
+                        break;
+                    }
+                }
+            }
+            candidate = self.prev[start % WINDOW_SIZE];
+        }
+
+        best
+    }
+}
+
+/// Last `WINDOW_SIZE` decoded symbols, which matches are copied from.
+pub struct Window {
+    symbols: Vec<u32>,
+    /// Number of symbols pushed so far.
+    pushed: usize,
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Window {
+            symbols: vec![0; WINDOW_SIZE],
+            pushed: 0,
+        }
+    }
+
+    #[inline]
+    pub fn push(&mut self, symbol: u32) {
+        self.symbols[self.pushed % WINDOW_SIZE] = symbol;
+        self.pushed += 1;
+    }
+
+    /// The symbol `dist` positions back, `None` when the window does not
+    /// reach that far.
+    #[inline]
+    pub fn get(&self, dist: usize) -> Option<u32> {
+        if dist == 0 || dist > self.pushed.min(WINDOW_SIZE) {
+            return None;
+        }
+        Some(self.symbols[(self.pushed - dist) % WINDOW_SIZE])
+    }
+}
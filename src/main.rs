@@ -1,18 +1,22 @@
-use std::{env, error::Error};
+use std::{env, process};
 
-use cchuff::run;
+use cchuff::{cli::Config, cli::USAGE, run};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = env::args();
-    args.next();
+fn main() {
+    let config = match Config::build(env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(e) => {
+            eprintln!("cchuff: {e}\nTry 'cchuff --help' for more information.");
+            process::exit(2);
+        }
+    };
 
-    match args.next() {
-        Some(in_path) => match args.next() {
-            Some(out_path) => run(&in_path, Some(&out_path))?,
-            None => run(&in_path, None)?,
-        },
-        None => panic!("Input file path required"),
+    // Failures are reported by `run` for every input
+    if run(&config).is_err() {
+        process::exit(1);
     }
-
-    Ok(())
 }
@@ -0,0 +1,36 @@
+#[cfg(feature = "parallel")]
+use std::{panic, thread};
+
+/// Runs `f` on every item and returns the results in order.
+///
+/// With the `parallel` feature the items are spread over up to `threads`
+/// scoped threads, otherwise they are processed one after the other.
+pub fn map<T, U, F>(items: &[T], threads: usize, f: F) -> Vec<U>
+where
+    T: Sync,
+    U: Send,
+    F: Fn(&T) -> U + Sync,
+{
+    #[cfg(feature = "parallel")]
+    if threads > 1 && items.len() > 1 {
+        let per_thread = items.len().div_ceil(threads);
+        let f = &f;
+
+        return thread::scope(|scope| {
+            let handles = items
+                .chunks(per_thread)
+                .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        });
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    let _ = threads;
+
+    items.iter().map(f).collect()
+}
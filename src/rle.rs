@@ -0,0 +1,113 @@
+use crate::{error::Error, shared::TOKEN_BASE};
+
+/// Runs shorter than this are left as repeated symbols, their tokens would
+/// cost about as much.
+const MIN_RUN: usize = 4;
+/// Most repeats a single token stands for, longer runs take several.
+pub const MAX_REPEATS: u32 = 256;
+/// Tokens of a run: `RUN_BASE + n - 1` repeats the symbol before it `n`
+/// more times.
+pub const RUN_BASE: u32 = TOKEN_BASE;
+
+/// Replaces the runs of `symbols` with their first symbol followed by run
+/// tokens for the repeats.
+pub fn transform(symbols: &[u32]) -> Vec<u32> {
+    let mut tokens = Vec::with_capacity(symbols.len());
+
+    for run in symbols.chunk_by(|a, b| a == b) {
+        if run.len() < MIN_RUN {
+            tokens.extend_from_slice(run);
+            continue;
+        }
+
+        tokens.push(run[0]);
+        let mut repeats = run.len() as u64 - 1;
+        while repeats > 0 {
+            let n = repeats.min(u64::from(MAX_REPEATS)) as u32;
+            tokens.push(RUN_BASE + n - 1);
+            repeats -= u64::from(n);
+        }
+    }
+
+    tokens
+}
+
+/// Number of repeats of run token `token`.
+#[inline]
+pub fn repeats(token: u32) -> Result<u32, Error> {
+    match token - RUN_BASE {
+        n if n < MAX_REPEATS => Ok(n + 1),
+        _ => Err(Error::InvalidBlock),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decoder, encoder::Encoder, shared::Mode};
+
+    fn encode(input: &[u8], mode: Mode, rle: bool, context: bool) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), mode)
+            .with_rle(rle)
+            .with_context(context)
+            .with_block_size(1000);
+        encoder.encode(&mut &input[..]).unwrap();
+
+        encoder.into_inner()
+    }
+
+    fn round_trip(input: &[u8], mode: Mode, context: bool) {
+        let encoded = encode(input, mode, true, context);
+        assert_eq!(decoder::decode(encoded).unwrap(), input);
+    }
+
+    #[test]
+    fn test_runs_longer_than_max_repeats() {
+        let mut input = vec![b'a'; MAX_REPEATS as usize * 3 + 2];
+        input.extend([b'b'; MAX_REPEATS as usize + 1]);
+        input.extend(b"cd");
+        assert!(transform(&input.iter().map(|&b| u32::from(b)).collect::<Vec<_>>()).len() < 10);
+
+        round_trip(&input, Mode::Bytes, false);
+    }
+
+    #[test]
+    fn test_runs_across_blocks() {
+        // Runs ending right at, starting right at and crossing the block
+        // boundaries of 1000 symbols
+        let mut input = vec![b'x'; 990];
+        input.extend([b'y'; 10]);
+        input.extend([b'z'; 1500]);
+        input.extend(b"0123456789".repeat(50));
+        input.extend([b'w'; 3]);
+
+        round_trip(&input, Mode::Bytes, false);
+    }
+
+    #[test]
+    fn test_runs_with_context() {
+        let input = "aaaaaaaabbbbbbbbbbbbbbbbcab ééééééé 🦀🦀🦀🦀🦀 ".repeat(100);
+        round_trip(input.as_bytes(), Mode::Text, true);
+        round_trip(input.as_bytes(), Mode::Bytes, true);
+    }
+
+    #[test]
+    fn test_no_worse_without_runs() {
+        // Short random runs gain nothing from run tokens, blocks keep their
+        // plain symbols then
+        let mut state = 1u64;
+        let input = (0..20_000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                b"ab"[(state >> 63) as usize]
+            })
+            .collect::<Vec<_>>();
+        let plain = encode(&input, Mode::Bytes, false, false);
+        let runs = encode(&input, Mode::Bytes, true, false);
+
+        assert!(runs.len() <= plain.len());
+        round_trip(&input, Mode::Bytes, false);
+    }
+}
@@ -1,14 +1,102 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Format version written by the encoder.
+pub const VERSION: u8 = 6;
+
+/// Block types, each block starts with one of these.
+pub const BLOCK_END: u8 = 0;
+pub const BLOCK_NEW_TABLE: u8 = 1;
+pub const BLOCK_REUSE_TABLE: u8 = 2;
+/// Block coded with an adaptive tree starting empty, no table is stored.
+pub const BLOCK_ADAPTIVE: u8 = 3;
+
+/// Header flag set when the payload symbols are raw bytes rather than chars.
+pub const FLAG_BYTES: u8 = 0b0000_0001;
+/// Header flag set when a CRC-32 of the whole content follows the last block.
+pub const FLAG_CHECKSUM: u8 = 0b0000_0010;
+/// Header flag set when every block is followed by the CRC-32 of its content.
+pub const FLAG_BLOCK_CHECKSUM: u8 = 0b0000_0100;
+/// Header flag set when code lengths are limited, a byte holding the limit
+/// follows the flags.
+pub const FLAG_MAX_CODE_LEN: u8 = 0b0000_1000;
+/// Header flag set when a block index ends the stream.
+pub const FLAG_BLOCK_INDEX: u8 = 0b0001_0000;
+/// Header flag set when every block is coded with adaptive Huffman codes.
+pub const FLAG_ADAPTIVE: u8 = 0b0010_0000;
+/// Header flag set when new tables hold one code table per context, the
+/// previous symbol picking the table of the next one.
+pub const FLAG_CONTEXT: u8 = 0b0100_0000;
+/// Header flag set when runs of a repeated symbol are coded as run tokens.
+pub const FLAG_RLE: u8 = 0b1000_0000;
+/// Every flag this version understands.
+pub const KNOWN_FLAGS: u8 = FLAG_BYTES
+    | FLAG_CHECKSUM
+    | FLAG_BLOCK_CHECKSUM
+    | FLAG_MAX_CODE_LEN
+    | FLAG_BLOCK_INDEX
+    | FLAG_ADAPTIVE
+    | FLAG_CONTEXT
+    | FLAG_RLE;
+
+/// First symbol standing for a token of a transform rather than content,
+/// above every Unicode scalar value.
+pub const TOKEN_BASE: u32 = 0x11_0000;
+
+/// Number of bits used to store each code length in the code table.
+pub const LENGTH_BITS: usize = 6;
+/// Longest code length the code table can store.
+pub const MAX_CODE_LEN: u8 = (1 << LENGTH_BITS) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Symbols are Unicode scalar values, the input must be valid UTF-8.
+    Text,
+    /// Symbols are the 256 possible `u8` values, any input is accepted.
+    Bytes,
+}
+
+impl Mode {
+    pub fn detect(input: &[u8]) -> Mode {
+        match std::str::from_utf8(input) {
+            Ok(_) => Mode::Text,
+            Err(_) => Mode::Bytes,
+        }
+    }
+
+    pub fn from_flags(flags: u8) -> Mode {
+        if flags & FLAG_BYTES != 0 {
+            Mode::Bytes
+        } else {
+            Mode::Text
+        }
+    }
+
+    pub fn flags(self) -> u8 {
+        match self {
+            Mode::Text => 0,
+            Mode::Bytes => FLAG_BYTES,
+        }
+    }
+
+    /// Number of bits used to store a leaf symbol in the serialized tree.
+    pub fn symbol_bits(self) -> usize {
+        match self {
+            Mode::Text => 32,
+            Mode::Bytes => 8,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct HuffNode {
-    pub value: Option<char>,
+    pub value: Option<u32>,
     pub freq: u32,
     pub left: Option<Box<HuffNode>>,
     pub right: Option<Box<HuffNode>>,
 }
 
-pub fn generate_queue(map: &BTreeMap<char, u32>) -> Vec<HuffNode> {
+/// Leaves for every symbol sorted by frequency, ties keep symbol order.
+pub fn generate_queue(map: &BTreeMap<u32, u32>) -> Vec<HuffNode> {
     let mut vec = map
         .iter()
         .map(|(k, v)| HuffNode {
@@ -24,22 +112,65 @@ pub fn generate_queue(map: &BTreeMap<char, u32>) -> Vec<HuffNode> {
     vec
 }
 
-pub fn generate_tree(mut nodes: Vec<HuffNode>) -> HuffNode {
-    while nodes.len() > 1 {
-        let curr = nodes.remove(0);
-        let next = nodes.remove(0);
+/// Builds the Huffman tree from leaves sorted by frequency, as returned by
+/// `generate_queue`.
+///
+/// Merged nodes are created in order of frequency, so they are kept in a
+/// second queue and the two lightest nodes are always at the front of one of
+/// the queues, which takes O(n) after the initial sort. On equal frequencies
+/// leaves are taken before merged nodes, so the same input always yields the
+/// same tree.
+pub fn generate_tree(nodes: Vec<HuffNode>) -> HuffNode {
+    let mut leaves = VecDeque::from(nodes);
+    let mut merged = VecDeque::with_capacity(leaves.len());
+
+    while leaves.len() + merged.len() > 1 {
+        let curr = pop_lightest(&mut leaves, &mut merged).unwrap();
+        let next = pop_lightest(&mut leaves, &mut merged).unwrap();
 
-        let node = HuffNode {
+        merged.push_back(HuffNode {
             value: None,
             freq: curr.freq + next.freq,
             left: Some(Box::new(curr)),
             right: Some(Box::new(next)),
-        };
+        });
+    }
+
+    leaves
+        .pop_front()
+        .or_else(|| merged.pop_front())
+        .expect("generate_tree needs at least one node")
+}
+
+fn pop_lightest(
+    leaves: &mut VecDeque<HuffNode>,
+    merged: &mut VecDeque<HuffNode>,
+) -> Option<HuffNode> {
+    match (leaves.front(), merged.front()) {
+        (Some(leaf), Some(node)) if node.freq < leaf.freq => merged.pop_front(),
+        (Some(_), _) => leaves.pop_front(),
+        (None, _) => merged.pop_front(),
+    }
+}
+
+/// Assigns canonical Huffman codes from per-symbol code lengths.
+///
+/// Symbols are ordered by code length and then by value, so the codes only
+/// depend on the lengths and both sides of the pipeline derive the same ones.
+pub fn canonical_codes(lengths: &BTreeMap<u32, u8>) -> BTreeMap<u32, (u64, u8)> {
+    let mut symbols = lengths.iter().collect::<Vec<_>>();
+    symbols.sort_by_key(|(symbol, len)| (**len, **symbol));
 
-        nodes.push(node);
+    let mut codes = BTreeMap::new();
+    let mut code = 0u64;
+    let mut prev_len = 0u8;
 
-        nodes.sort_by_key(|v| v.freq);
+    for (&symbol, &len) in symbols {
+        code <<= len - prev_len;
+        codes.insert(symbol, (code, len));
+        code += 1;
+        prev_len = len;
     }
 
-    nodes.remove(0)
+    codes
 }
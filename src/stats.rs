@@ -0,0 +1,76 @@
+use std::{fmt, time::Duration};
+
+/// Figures collected while encoding or decoding a stream, returned by
+/// `Encoder::encode` and `Decoder::decode`.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// Bits of the compressed stream not spent on coded symbols: headers,
+    /// code tables, lengths, checksums and padding.
+    pub header_bits: u64,
+    /// Bits spent on coded symbols.
+    pub payload_bits: u64,
+    pub blocks: u64,
+    pub symbols: u64,
+    /// Number of different symbols across all blocks.
+    pub distinct_symbols: u64,
+    /// Time spent building code tables when encoding, or reading them when
+    /// decoding.
+    pub table_time: Duration,
+    /// Time spent coding symbols.
+    pub payload_time: Duration,
+    pub total_time: Duration,
+}
+
+impl Stats {
+    /// Average number of bits per coded symbol.
+    pub fn avg_code_len(&self) -> f64 {
+        if self.symbols == 0 {
+            return 0.0;
+        }
+        self.payload_bits as f64 / self.symbols as f64
+    }
+
+    /// Formats the statistics as a single line JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            concat!(
+                "{{\"input_bytes\":{},\"output_bytes\":{},\"header_bits\":{},",
+                "\"payload_bits\":{},\"blocks\":{},\"symbols\":{},\"distinct_symbols\":{},",
+                "\"avg_code_len\":{:.4},\"table_secs\":{:.6},\"payload_secs\":{:.6},",
+                "\"total_secs\":{:.6}}}"
+            ),
+            self.input_bytes,
+            self.output_bytes,
+            self.header_bits,
+            self.payload_bits,
+            self.blocks,
+            self.symbols,
+            self.distinct_symbols,
+            self.avg_code_len(),
+            self.table_time.as_secs_f64(),
+            self.payload_time.as_secs_f64(),
+            self.total_time.as_secs_f64(),
+        )
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  input:       {} bytes", self.input_bytes)?;
+        writeln!(f, "  output:      {} bytes", self.output_bytes)?;
+        writeln!(f, "  header:      {} bits", self.header_bits)?;
+        writeln!(f, "  payload:     {} bits", self.payload_bits)?;
+        writeln!(f, "  blocks:      {}", self.blocks)?;
+        writeln!(
+            f,
+            "  symbols:     {} ({} distinct)",
+            self.symbols, self.distinct_symbols
+        )?;
+        writeln!(f, "  avg code:    {:.3} bits", self.avg_code_len())?;
+        writeln!(f, "  table time:  {:?}", self.table_time)?;
+        writeln!(f, "  code time:   {:?}", self.payload_time)?;
+        write!(f, "  total time:  {:?}", self.total_time)
+    }
+}